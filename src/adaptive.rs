@@ -0,0 +1,186 @@
+use image::{ImageBuffer, Rgb};
+
+#[cfg(test)]
+use crate::camera::{off_center_sphere, render_with, sky_lit_sphere};
+use crate::{
+    bsdf::luminance,
+    camera::{
+        for_each_row, for_each_row_zip, gather, sample_pixel, splatted, to_image, Camera,
+        PixelSample, Region, RenderSettings, RowPair, Splat,
+    },
+    filter::Filter,
+    progressive::relative_error,
+    ray_rgb::RayRgb,
+    sampler::Sampler,
+    world::World,
+};
+
+// Each pixel takes rounds of `min_samples` samples until its relative error, as in
+// `Accumulator::noise`, drops to `threshold` or it has taken `max_samples`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveSampling {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f32,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        Self {
+            min_samples: 8,
+            max_samples: 256,
+            threshold: 0.01,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptiveRender {
+    pub image: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    // Samples taken by each pixel, row by row.
+    pub samples: Vec<u32>,
+}
+
+impl AdaptiveRender {
+    pub fn samples_at(&self, x: u32, y: u32) -> u32 {
+        self.samples[(y * self.image.width() + x) as usize]
+    }
+
+    // How hard each pixel worked, from black at `min_samples` through red and yellow to
+    // white at `max_samples`.
+    pub fn heat_map(&self, adaptive: &AdaptiveSampling) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let range = adaptive
+            .max_samples
+            .saturating_sub(adaptive.min_samples)
+            .max(1) as f32;
+        ImageBuffer::from_fn(self.image.width(), self.image.height(), |x, y| {
+            let extra = self.samples_at(x, y).saturating_sub(adaptive.min_samples);
+            let t = 3.0 * extra as f32 / range;
+            RayRgb::new(t, t - 1.0, t - 2.0).to_rgb()
+        })
+    }
+}
+
+// Renders with as many samples in each pixel as its noise calls for. Flat regions stop
+// after the first round. Samples are weighted into the pixels around them by
+// `settings.filter`; `settings.samples_per_pixel` is ignored.
+pub fn render_adaptive(
+    camera: &Camera,
+    world: &World,
+    settings: &RenderSettings,
+    adaptive: &AdaptiveSampling,
+) -> AdaptiveRender {
+    let (width, height) = (camera.hsize, camera.vsize);
+    let mut taken: Vec<Vec<PixelSample>> = vec![Vec::new(); (width * height) as usize];
+    // The number of samples each pixel took, and the light they traced to the camera.
+    let mut counts: Vec<(u32, Vec<Splat>)> = vec![(0, Vec::new()); (width * height) as usize];
+    let round = adaptive.min_samples.max(2);
+    let max_samples = adaptive.max_samples.max(round);
+    // Under the default filter each pixel need only keep its mean (see `Filter::default`).
+    let keep_samples = settings.filter != Filter::default();
+
+    let sample_row = |(y, (pixels, counts)): RowPair<Vec<PixelSample>, (u32, Vec<Splat>)>| {
+        for (x, (pixel, (count, splats))) in pixels.iter_mut().zip(counts).enumerate() {
+            let (x, y) = (x as u32, y as u32);
+            let mut sampler = Sampler::new(settings.sampler, settings.seed, x, y);
+            let mut color = RayRgb::black();
+            let (mut sum, mut sum_squares) = (0.0, 0.0);
+            let mut n = 0;
+            while n < max_samples
+                && (n < round || relative_error(sum, sum_squares, n) > adaptive.threshold)
+            {
+                for i in 0..round.min(max_samples - n) {
+                    // Rounds take their places in one stratification over
+                    // `max_samples`, rather than each covering the pixel again.
+                    sampler.start_sample(n + i, max_samples);
+                    let (dx, dy) = settings
+                        .pixel_sampling
+                        .offset(n + i, max_samples, &mut sampler);
+                    let (px, py) = (x as f32 + dx, y as f32 + dy);
+                    let sample =
+                        sample_pixel(camera, world, settings, px, py, &mut sampler, splats);
+                    if keep_samples {
+                        pixel.push((px, py, sample));
+                    }
+                    color = color + sample;
+                    sum += luminance(&sample);
+                    sum_squares += luminance(&sample).powi(2);
+                }
+                n += round.min(max_samples - n);
+            }
+            if !keep_samples {
+                pixel.push((x as f32 + 0.5, y as f32 + 0.5, color * (1.0 / n as f32)));
+            }
+            *count = n;
+        }
+    };
+    let full = Region::full(camera);
+    let mut colors = vec![RayRgb::black(); (width * height) as usize];
+    if width > 0 {
+        let width = width as usize;
+        for_each_row_zip(
+            settings.threads,
+            (&mut taken, width),
+            (&mut counts, width),
+            sample_row,
+        );
+
+        let gather_row = |(y, pixels): (usize, &mut [RayRgb])| {
+            for (x, pixel) in pixels.iter_mut().enumerate() {
+                let (color, total) = gather(&settings.filter, &taken, &full, x as u32, y as u32);
+                *pixel = if total > 0.0 {
+                    color * (1.0 / total)
+                } else {
+                    color
+                };
+            }
+        };
+        for_each_row(settings.threads, &mut colors, width, gather_row);
+    }
+    let samples: Vec<u32> = counts.iter().map(|&(n, _)| n).collect();
+    let splats: Vec<&Vec<Splat>> = counts.iter().map(|(_, splats)| splats).collect();
+    let splatted = splatted(camera, &full, &splats, samples.iter().sum());
+    AdaptiveRender {
+        image: to_image(&full, |i| colors[i] + splatted[i]),
+        samples,
+    }
+}
+
+#[test]
+fn test_adaptive_sampling() {
+    let (world, camera, settings) = sky_lit_sphere();
+    let adaptive = AdaptiveSampling {
+        min_samples: 4,
+        max_samples: 64,
+        threshold: 0.02,
+    };
+    let render = render_adaptive(&camera, &world, &settings, &adaptive);
+
+    assert_eq!(render.samples_at(0, 0), 4);
+    assert_eq!(render.samples_at(15, 11), 4);
+    assert!((7..=8).any(|x| render.samples_at(x, 6) > 4));
+    assert!(render.samples.iter().all(|&n| (4..=64).contains(&n)));
+    assert!(render.samples.iter().filter(|&&n| n == 4).count() > 150);
+
+    let heat_map = render.heat_map(&adaptive);
+    assert_eq!(heat_map.get_pixel(0, 0), &Rgb([0, 0, 0]));
+    let hottest = (0..16 * 12).max_by_key(|&i| render.samples[i]).unwrap() as u32;
+    assert!(heat_map.get_pixel(hottest % 16, hottest / 16).0[0] > 0);
+}
+
+#[test]
+fn test_adaptive_filter() {
+    // With a single round, an adaptive render takes the same samples as a filtered
+    // render and weights them the same way.
+    let (world, camera, settings) = off_center_sphere();
+    let expected = render_with(&camera, &world, &settings);
+    let adaptive = AdaptiveSampling {
+        min_samples: 4,
+        max_samples: 4,
+        threshold: 0.0,
+    };
+    let render = render_adaptive(&camera, &world, &settings, &adaptive);
+    for (a, b) in render.image.pixels().zip(expected.pixels()) {
+        assert!((0..3).all(|c| a.0[c].abs_diff(b.0[c]) <= 1));
+    }
+}
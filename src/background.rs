@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+#[cfg(test)]
+use approx::assert_relative_eq;
+#[cfg(test)]
+use image::DynamicImage;
+use rapier3d::na::{Point3, Vector3};
+
+#[cfg(test)]
+use crate::texture::UvMapping;
+use crate::{
+    ray_rgb::RayRgb,
+    sky::Sky,
+    texture::{ImageTexture, Texture, WrapMode},
+};
+
+// What rays that miss every object see when the world has no environment map.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Background {
+    Color(RayRgb),
+    // Blends from `bottom` for rays going straight down to `top` straight up.
+    Gradient { top: RayRgb, bottom: RayRgb },
+    // An image stretched over the camera's frame. Other rays see its average color.
+    Screen(Arc<ImageTexture>),
+    // An image looked up by ray direction, usually with `UvMapping::Spherical`.
+    Directional(Texture),
+    // The daylight model evaluated along each ray. Bake it with `Sky::environment` to
+    // importance sample it as a light instead.
+    Sky(Sky),
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Color(RayRgb::black())
+    }
+}
+
+impl Background {
+    pub fn color_at(&self, dir: &Vector3<f32>) -> RayRgb {
+        let dir = dir.normalize();
+        match self {
+            Background::Color(color) => *color,
+            Background::Gradient { top, bottom } => {
+                let t = (dir.y + 1.0) / 2.0;
+                *bottom * (1.0 - t) + *top * t
+            }
+            Background::Screen(image) => image.average(),
+            Background::Directional(texture) => texture.color_at(&Point3::from(dir), &dir),
+            Background::Sky(sky) => sky.radiance(&dir),
+        }
+    }
+
+    // Color seen by a camera ray through (u, v) of the frame, with v = 0 at the bottom.
+    pub fn screen_color(&self, u: f32, v: f32, dir: &Vector3<f32>) -> RayRgb {
+        match self {
+            Background::Screen(image) => image.sample(u, v, WrapMode::Clamp),
+            _ => self.color_at(dir),
+        }
+    }
+
+    // Average over all directions, used when the background lights the scene as
+    // ambient light.
+    pub fn average(&self) -> RayRgb {
+        match self {
+            Background::Color(color) => *color,
+            Background::Gradient { top, bottom } => (*top + *bottom) * 0.5,
+            Background::Screen(image) => image.average(),
+            Background::Directional(texture) => texture.image.average(),
+            Background::Sky(sky) => sky.average(),
+        }
+    }
+}
+
+#[test]
+fn test_background() {
+    let gradient = Background::Gradient {
+        top: RayRgb::new(0.2, 0.4, 1.0),
+        bottom: RayRgb::white(),
+    };
+    assert_eq!(
+        gradient.color_at(&Vector3::new(0.0, 2.0, 0.0)),
+        RayRgb::new(0.2, 0.4, 1.0)
+    );
+    assert_relative_eq!(gradient.color_at(&Vector3::new(1.0, 0.0, 0.0)).r, 0.6);
+    assert_relative_eq!(gradient.average().g, 0.7);
+
+    let mut image = image::RgbImage::new(2, 1);
+    image.put_pixel(1, 0, image::Rgb([255, 255, 255]));
+    let image = ImageTexture::from_image(&DynamicImage::ImageRgb8(image));
+    let screen = Background::Screen(Arc::new(image.clone()));
+    let dir = Vector3::new(0.0, 0.0, -1.0);
+    assert_eq!(screen.screen_color(0.9, 0.5, &dir), RayRgb::white());
+    assert_eq!(screen.screen_color(0.1, 0.5, &dir), RayRgb::black());
+    assert_relative_eq!(screen.color_at(&dir).r, 0.5);
+
+    let directional = Background::Directional(Texture::new(image, UvMapping::Spherical));
+    assert_eq!(
+        directional.color_at(&Vector3::new(-1.0, 0.0, 0.0)),
+        RayRgb::white()
+    );
+
+    let sky = Sky::new(0.5, 0.0, 3.0);
+    let dir = Vector3::new(0.3, 0.4, -0.8);
+    assert_eq!(Background::Sky(sky).color_at(&dir), sky.radiance(&dir));
+    let average = Background::Sky(sky).average();
+    assert!(average.b > 0.0 && average.b < sky.radiance(&sky.sun_direction()).b);
+}
@@ -0,0 +1,710 @@
+use std::f32::consts::PI;
+
+#[cfg(test)]
+use crate::integrator::Integrator;
+use rand::Rng;
+#[cfg(test)]
+use rand::{rngs::StdRng, SeedableRng};
+#[cfg(test)]
+use rapier3d::na::Isometry3;
+use rapier3d::{
+    na::{Point3, Vector3},
+    prelude::*,
+};
+
+use crate::{
+    bsdf::{to_world, Bsdf},
+    camera::{Camera, Splat},
+    integrator::MisHeuristic,
+    intersections::prepare_computations,
+    light::{as_emitter, emitters, surface_color, ENVIRONMENT_DISTANCE},
+    ray_rgb::RayRgb,
+    shape::ShapeT,
+    sphere::Sphere,
+    world::{background, hit_world, is_occluded, World},
+};
+
+// A vertex of a camera or light subpath. Densities are stored per unit area so that
+// subpaths sampled from either end can be compared when computing MIS weights.
+#[derive(Debug, Clone, Copy)]
+struct Vertex<'a> {
+    point: Point3<f32>,
+    normal: Vector3<f32>,
+    wo: Vector3<f32>,
+    object: Option<&'a dyn ShapeT>,
+    // The pinhole camera a camera vertex belongs to, when light can be traced to it.
+    camera: Option<&'a Camera>,
+    bsdf: Option<Bsdf>,
+    inside: bool,
+    // Whether the subpath left this vertex by a mirror or refraction, in a single
+    // direction that no other strategy can sample.
+    delta: bool,
+    beta: RayRgb,
+    pdf_fwd: f32,
+    pdf_rev: f32,
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(point: Point3<f32>, camera: Option<&'a Camera>) -> Self {
+        Self {
+            point,
+            normal: Vector3::zeros(),
+            wo: Vector3::zeros(),
+            object: None,
+            camera,
+            bsdf: None,
+            inside: false,
+            delta: false,
+            beta: RayRgb::white(),
+            pdf_fwd: 1.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn light(emitter: &'a Sphere, point: Point3<f32>, normal: Vector3<f32>, pdf: f32) -> Self {
+        Self {
+            point,
+            normal,
+            wo: normal,
+            object: Some(emitter),
+            camera: None,
+            bsdf: None,
+            inside: false,
+            delta: false,
+            beta: emitter.material.emitted() * (1.0 / pdf),
+            pdf_fwd: pdf,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn is_light(&self) -> bool {
+        self.object.is_some() && self.bsdf.is_none()
+    }
+
+    fn f(&self, next: &Vertex) -> RayRgb {
+        match &self.bsdf {
+            Some(bsdf) => bsdf.eval(&self.wo, &(next.point - self.point).normalize()),
+            None => RayRgb::black(),
+        }
+    }
+
+    // Radiance this vertex emits towards `towards`.
+    fn emitted(&self, towards: &Vertex) -> RayRgb {
+        match self.object {
+            Some(object) if object.material().is_emissive() && !self.inside => {
+                if self.normal.dot(&(towards.point - self.point)) > 0.0 {
+                    object.material().emitted()
+                } else {
+                    RayRgb::black()
+                }
+            }
+            _ => RayRgb::black(),
+        }
+    }
+
+    // Area density with which `next` is sampled from this vertex, having arrived from `prev`.
+    fn pdf(&self, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let wn = (next.point - self.point).normalize();
+        let pdf = if let Some(camera) = self.camera {
+            camera.pdf_direction(&wn)
+        } else if self.is_light() {
+            self.pdf_light_dir(&wn)
+        } else {
+            match (&self.bsdf, prev) {
+                (Some(bsdf), Some(prev)) => bsdf.pdf(&(prev.point - self.point).normalize(), &wn),
+                _ => 0.0,
+            }
+        };
+        convert_density(pdf, self, next)
+    }
+
+    // Area density with which an emitting surface vertex sends light towards `next`.
+    fn pdf_light(&self, next: &Vertex) -> f32 {
+        let wn = (next.point - self.point).normalize();
+        convert_density(self.pdf_light_dir(&wn), self, next)
+    }
+
+    fn pdf_light_dir(&self, w: &Vector3<f32>) -> f32 {
+        self.normal.dot(w).max(0.0) / PI
+    }
+}
+
+fn convert_density(pdf: f32, from: &Vertex, to: &Vertex) -> f32 {
+    let v = to.point - from.point;
+    let distance_squared = v.magnitude_squared();
+    if distance_squared == 0.0 {
+        return 0.0;
+    }
+    pdf * to.normal.dot(&(v / distance_squared.sqrt())).abs() / distance_squared
+}
+
+fn geometry(a: &Vertex, b: &Vertex) -> f32 {
+    let v = b.point - a.point;
+    let distance_squared = v.magnitude_squared();
+    let d = v / distance_squared.sqrt();
+    a.normal.dot(&d).abs() * b.normal.dot(&d).abs() / distance_squared
+}
+
+// Offsets a vertex off its surface on the side facing `towards`.
+fn nudge(v: &Vertex, towards: &Point3<f32>) -> Point3<f32> {
+    let side = if v.normal.dot(&(towards - v.point)) >= 0.0 {
+        1.0
+    } else {
+        -1.0
+    };
+    v.point + v.normal * side * f32::EPSILON * 100.0
+}
+
+fn visible(world: &World, a: &Vertex, b: &Vertex) -> bool {
+    !is_occluded(world, &nudge(a, &b.point), &nudge(b, &a.point))
+}
+
+fn random_walk<'a, R: Rng>(
+    world: &'a World,
+    ray: Ray,
+    beta: RayRgb,
+    pdf: f32,
+    max_vertices: usize,
+    vertices: &mut Vec<Vertex<'a>>,
+    rng: &mut R,
+) {
+    let mut ray = ray;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf;
+    while vertices.len() < max_vertices {
+        let hit = match hit_world(world, &ray) {
+            Some(hit) => hit,
+            None => break,
+        };
+        let comps = prepare_computations(&hit, &ray);
+        let material = comps.object.material();
+        let bsdf = Bsdf::at(
+            &comps,
+            surface_color(material, comps.object, comps.over_point),
+        );
+        let prev = vertices[vertices.len() - 1];
+        let mut vertex = Vertex {
+            point: comps.point,
+            normal: comps.normalv,
+            wo: comps.eyev,
+            object: Some(hit.object),
+            camera: None,
+            bsdf: Some(bsdf),
+            inside: comps.inside,
+            delta: false,
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        };
+        vertex.pdf_fwd = convert_density(pdf_fwd, &prev, &vertex);
+        vertices.push(vertex);
+        if vertices.len() >= max_vertices {
+            break;
+        }
+
+        let sample = match bsdf.sample(&comps.eyev, rng) {
+            Some(sample) => sample,
+            None => break,
+        };
+        let n = vertices.len();
+        if sample.specular {
+            // Densities of delta lobes are left at zero, which the MIS weights skip.
+            beta = beta * sample.f * (1.0 / sample.pdf);
+            pdf_fwd = 0.0;
+            vertices[n - 1].delta = true;
+        } else {
+            beta = beta * sample.f * (sample.wi.dot(&bsdf.normal).abs() / sample.pdf);
+            pdf_fwd = sample.pdf;
+            let pdf_rev = bsdf.pdf(&sample.wi, &comps.eyev);
+            vertices[n - 2].pdf_rev = convert_density(pdf_rev, &vertex, &prev);
+        }
+        let origin = if sample.wi.dot(&comps.normalv) < 0.0 {
+            comps.under_point
+        } else {
+            comps.over_point
+        };
+        ray = Ray::new(origin, sample.wi);
+    }
+}
+
+// The camera subpath starting with `ray`. Through a pinhole camera, the ray's density
+// is that of the camera's rays over the whole image, to weigh against light tracing.
+// Other cameras have no light tracing to weigh against.
+fn camera_subpath<'a, R: Rng>(
+    world: &'a World,
+    ray: &Ray,
+    camera: Option<&'a Camera>,
+    max_vertices: usize,
+    rng: &mut R,
+) -> Vec<Vertex<'a>> {
+    let mut vertices = vec![Vertex::camera(ray.origin, camera)];
+    random_walk(
+        world,
+        *ray,
+        RayRgb::white(),
+        camera.map_or(1.0, |camera| camera.pdf_direction(&ray.dir)),
+        max_vertices,
+        &mut vertices,
+        rng,
+    );
+    vertices
+}
+
+fn light_subpath<'a, R: Rng>(
+    world: &'a World,
+    max_vertices: usize,
+    rng: &mut R,
+) -> Vec<Vertex<'a>> {
+    let mut vertices = Vec::new();
+    let vertex = match sample_emitter(world, rng) {
+        Some(vertex) => vertex,
+        None => return vertices,
+    };
+    vertices.push(vertex);
+
+    let (u1, u2): (f32, f32) = (rng.gen(), rng.gen());
+    let r = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    let dir = to_world(
+        &vertex.normal,
+        Vector3::new(r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt()),
+    );
+    let pdf_dir = vertex.pdf_light_dir(&dir);
+    if pdf_dir <= 0.0 {
+        return vertices;
+    }
+    let origin = vertex.point + vertex.normal * f32::EPSILON * 100.0;
+    let beta = vertex.beta * (vertex.normal.dot(&dir) / pdf_dir);
+    random_walk(
+        world,
+        Ray::new(origin, dir),
+        beta,
+        pdf_dir,
+        max_vertices,
+        &mut vertices,
+        rng,
+    );
+    vertices
+}
+
+// Picks an emitter uniformly and a point uniformly on its surface.
+fn sample_emitter<'a, R: Rng>(world: &'a World, rng: &mut R) -> Option<Vertex<'a>> {
+    let count = emitters(world).count();
+    if count == 0 {
+        return None;
+    }
+    let emitter = emitters(world).nth(rng.gen_range(0..count))?;
+    let (p, n) = emitter.sample_surface(rng.gen(), rng.gen());
+    Some(Vertex::light(
+        emitter,
+        p,
+        n,
+        pdf_light_origin(world, emitter),
+    ))
+}
+
+// Area density with which a light subpath starts at a point on `object`, which is zero
+// for shapes `sample_light` cannot pick.
+fn pdf_light_origin(world: &World, object: &dyn ShapeT) -> f32 {
+    match as_emitter(world, object) {
+        Some(emitter) => 1.0 / (emitters(world).count() as f32 * emitter.area()),
+        None => 0.0,
+    }
+}
+
+// Light from the point lights reaching camera vertex `pt`. Point lights cannot be hit
+// or emitted from under the no-falloff Phong convention, so this is their only strategy.
+fn point_lights(world: &World, pt: &Vertex) -> RayRgb {
+    let bsdf = match &pt.bsdf {
+        Some(bsdf) => bsdf,
+        None => return RayRgb::black(),
+    };
+    let mut color = RayRgb::black();
+    for light in &world.lights {
+        let wi = (light.position - pt.point).normalize();
+        let f = bsdf.eval(&pt.wo, &wi);
+        if f == RayRgb::black() || is_occluded(world, &nudge(pt, &light.position), &light.position)
+        {
+            continue;
+        }
+        color = color + f * light.color * (light.intensity as f32 * PI * wi.dot(&bsdf.normal));
+    }
+    color
+}
+
+// Light from the environment map, or an ambient background, reaching camera vertex
+// `pt`. Neither has a surface to start light subpaths from, so it is gathered here with
+// its own two strategies, one environment sample and one BSDF sample, combined with MIS.
+// An ambient background can only be found by the BSDF sample.
+fn environment<R: Rng>(world: &World, pt: &Vertex, heuristic: MisHeuristic, rng: &mut R) -> RayRgb {
+    let bsdf = match &pt.bsdf {
+        Some(bsdf) => bsdf,
+        None => return RayRgb::black(),
+    };
+    if world.environment.is_none() && !world.ambient_background {
+        return RayRgb::black();
+    }
+    let mut color = RayRgb::black();
+
+    if let Some(environment) = &world.environment {
+        if let Some((wi, radiance, pdf)) = environment.sample(rng) {
+            let f = bsdf.eval(&pt.wo, &wi);
+            let target = pt.point + wi * ENVIRONMENT_DISTANCE;
+            if f != RayRgb::black() && !is_occluded(world, &nudge(pt, &target), &target) {
+                let weight = heuristic.weight(pdf, bsdf.pdf(&pt.wo, &wi));
+                color = color + f * radiance * (wi.dot(&bsdf.normal).abs() * weight / pdf);
+            }
+        }
+    }
+
+    if let Some(sample) = bsdf.sample(&pt.wo, rng) {
+        let target = pt.point + sample.wi * ENVIRONMENT_DISTANCE;
+        if !is_occluded(world, &nudge(pt, &target), &target) {
+            // Environment samples cannot find a mirror or refraction direction.
+            let (radiance, weight) = match &world.environment {
+                Some(environment) if !sample.specular => (
+                    environment.radiance(&sample.wi),
+                    heuristic.weight(sample.pdf, environment.pdf(&sample.wi)),
+                ),
+                Some(environment) => (environment.radiance(&sample.wi), 1.0),
+                None => (world.background.color_at(&sample.wi), 1.0),
+            };
+            let cos_theta = if sample.specular {
+                1.0
+            } else {
+                sample.wi.dot(&bsdf.normal).abs()
+            };
+            color = color + sample.f * radiance * (cos_theta * weight / sample.pdf);
+        }
+    }
+    color
+}
+
+// Unweighted contribution of the path built from `s` light and `t` camera vertices,
+// together with the light vertex sampled for it when `s == 1`.
+fn connect<'a, R: Rng>(
+    world: &'a World,
+    light: &[Vertex<'a>],
+    camera: &[Vertex<'a>],
+    s: usize,
+    t: usize,
+    rng: &mut R,
+) -> (RayRgb, Option<Vertex<'a>>) {
+    let pt = &camera[t - 1];
+    match s {
+        0 => (pt.beta * pt.emitted(&camera[t - 2]), None),
+        1 => {
+            let sampled = match sample_emitter(world, rng) {
+                Some(sampled) => sampled,
+                None => return (RayRgb::black(), None),
+            };
+            let le = sampled.emitted(pt);
+            let f = pt.f(&sampled);
+            if le == RayRgb::black() || f == RayRgb::black() || !visible(world, pt, &sampled) {
+                return (RayRgb::black(), None);
+            }
+            (
+                pt.beta * f * sampled.beta * geometry(pt, &sampled),
+                Some(sampled),
+            )
+        }
+        _ => {
+            let qs = &light[s - 1];
+            let l = qs.beta * qs.f(pt) * pt.f(qs) * pt.beta;
+            if l == RayRgb::black() || !visible(world, qs, pt) {
+                return (RayRgb::black(), None);
+            }
+            (l * geometry(qs, pt), None)
+        }
+    }
+}
+
+// Unweighted contribution of the path joining light vertex `s - 1` straight to the
+// pinhole camera at `camera`, and the image point it lands on.
+fn connect_camera(
+    world: &World,
+    light: &[Vertex],
+    camera: &Vertex,
+    s: usize,
+) -> Option<(f32, f32, RayRgb)> {
+    let pinhole = camera.camera?;
+    let qs = &light[s - 1];
+    let dir = qs.point - camera.point;
+    let (x, y) = pinhole.image_point(&dir)?;
+    // The pinhole's importance, times the cosine at the pinhole, is the density of its
+    // rays over the image.
+    let l = qs.beta * qs.f(camera) * convert_density(pinhole.pdf_direction(&dir), camera, qs);
+    if l == RayRgb::black() || !visible(world, qs, camera) {
+        return None;
+    }
+    Some((x, y, l))
+}
+
+fn mis_weight(
+    world: &World,
+    light: &[Vertex],
+    camera: &[Vertex],
+    sampled: Option<&Vertex>,
+    s: usize,
+    t: usize,
+    heuristic: MisHeuristic,
+) -> f32 {
+    if s + t == 2 {
+        return 1.0;
+    }
+    // Densities and delta flags of the vertices, updated below for this connection.
+    let mut cam: Vec<(f32, f32, bool)> = camera[..t]
+        .iter()
+        .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+        .collect();
+    let mut lig: Vec<(f32, f32, bool)> = light[..s.min(light.len())]
+        .iter()
+        .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+        .collect();
+    if let Some(sampled) = sampled {
+        lig = vec![(sampled.pdf_fwd, sampled.pdf_rev, sampled.delta)];
+    }
+
+    let pt = &camera[t - 1];
+    let pt_minus = if t > 1 { Some(&camera[t - 2]) } else { None };
+    let qs = match sampled {
+        Some(sampled) => Some(sampled),
+        None if s > 0 => Some(&light[s - 1]),
+        None => None,
+    };
+    let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+
+    // The connected vertices scatter through their non-delta lobes.
+    cam[t - 1].2 = false;
+    if s > 0 {
+        lig[s - 1].2 = false;
+    }
+    cam[t - 1].1 = match (qs, pt.object) {
+        (Some(qs), _) => qs.pdf(qs_minus, pt),
+        (None, Some(object)) => pdf_light_origin(world, object),
+        (None, None) => 0.0,
+    };
+    if let Some(pt_minus) = pt_minus {
+        cam[t - 2].1 = match qs {
+            Some(qs) => pt.pdf(Some(qs), pt_minus),
+            None => pt.pdf_light(pt_minus),
+        };
+    }
+    if let Some(qs) = qs {
+        lig[s - 1].1 = pt.pdf(pt_minus, qs);
+        if let Some(qs_minus) = qs_minus {
+            lig[s - 2].1 = qs.pdf(Some(pt), qs_minus);
+        }
+    }
+
+    // A strategy that would connect at a delta vertex cannot sample the path, and one
+    // with a single camera vertex is light tracing, which only a pinhole camera has.
+    let remap = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+    let first = if camera[0].camera.is_some() { 1 } else { 2 };
+    let mut sum = 0.0;
+    let mut ratio = 1.0;
+    for i in (first..t).rev() {
+        ratio *= remap(cam[i].1) / remap(cam[i].0);
+        if !cam[i].2 && !cam[i - 1].2 {
+            sum += heuristic.remap(ratio);
+        }
+    }
+    ratio = 1.0;
+    for i in (0..lig.len()).rev() {
+        ratio *= remap(lig[i].1) / remap(lig[i].0);
+        if !lig[i].2 && (i == 0 || !lig[i - 1].2) {
+            sum += heuristic.remap(ratio);
+        }
+    }
+    1.0 / (1.0 + sum)
+}
+
+// Radiance along `ray`. Given a pinhole camera the ray comes from, light subpaths are
+// also joined straight to the camera, and what they carry is added to `splats` at the
+// image points it lands on.
+pub fn trace_bidirectional<R: Rng>(
+    world: &World,
+    ray: &Ray,
+    max_depth: usize,
+    heuristic: MisHeuristic,
+    pinhole: Option<&Camera>,
+    splats: &mut Vec<Splat>,
+    rng: &mut R,
+) -> RayRgb {
+    let camera = camera_subpath(world, ray, pinhole, max_depth + 2, rng);
+    let light = light_subpath(world, max_depth + 1, rng);
+
+    for s in 2..=light.len().min(max_depth + 1) {
+        if let Some((x, y, l)) = connect_camera(world, &light, &camera[0], s) {
+            let weight = mis_weight(world, &light, &camera, None, s, 1, heuristic);
+            splats.push((x, y, l * weight));
+        }
+    }
+
+    let mut radiance = RayRgb::black();
+    if camera.len() == 1 {
+        radiance = background(world, ray);
+    }
+    for t in 2..=camera.len() {
+        if t - 1 <= max_depth {
+            radiance = radiance
+                + camera[t - 1].beta
+                    * (point_lights(world, &camera[t - 1])
+                        + environment(world, &camera[t - 1], heuristic, rng));
+        }
+        for s in 0..=light.len().max(1) {
+            if s + t - 2 > max_depth {
+                continue;
+            }
+            let (l, sampled) = connect(world, &light, &camera, s, t, rng);
+            if l == RayRgb::black() {
+                continue;
+            }
+            let weight = mis_weight(world, &light, &camera, sampled.as_ref(), s, t, heuristic);
+            radiance = radiance + l * weight;
+        }
+    }
+    radiance
+}
+
+// Mean of `n` samples of `sample` and the standard error of that mean.
+#[cfg(test)]
+fn estimate<F: FnMut() -> f32>(n: usize, mut sample: F) -> (f32, f32) {
+    let (mut sum, mut sum_squares) = (0.0, 0.0);
+    for _ in 0..n {
+        let x = sample() as f64;
+        sum += x;
+        sum_squares += x * x;
+    }
+    let mean = sum / n as f64;
+    let variance = (sum_squares / n as f64 - mean * mean) * n as f64 / (n as f64 - 1.0);
+    (mean as f32, (variance / n as f64).sqrt() as f32)
+}
+
+#[cfg(test)]
+fn lamp_over_floor() -> World {
+    let mut lamp = Sphere::new(Point3::new(0.0, 3.0, 0.0), 1.0);
+    lamp.material.emission = RayRgb::white();
+    lamp.material.emission_strength = 5.0;
+    let mut floor = Sphere::new(Point3::new(0.0, -100.0, 0.0), 100.0);
+    floor.material.specular = 0.0;
+    floor.material.diffuse = 0.5;
+    World {
+        lights: vec![],
+        objects: vec![lamp, floor],
+        emitter_samples: 4096,
+        ..World::default()
+    }
+}
+
+#[test]
+fn test_bidirectional_matches_direct_lighting() {
+    let w = lamp_over_floor();
+    let integrator = Integrator::Bidirectional {
+        max_depth: 1,
+        heuristic: MisHeuristic::Balance,
+    };
+    let mut rng = StdRng::seed_from_u64(7);
+
+    let r = Ray::new(Point3::new(0.0, 3.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+    assert!(integrator.color_at(&w, &r, &mut rng).r >= 5.0);
+
+    // The ray meets the floor at the origin, right below the lamp. A sphere of radiance
+    // L and radius R at distance d lights it with irradiance pi L (R / d)^2, which the
+    // floor reflects with albedo 0.5.
+    let r = Ray::new(
+        Point3::new(0.0, 1.0, -5.0),
+        Vector3::new(0.0, -0.2, 1.0).normalize(),
+    );
+    let expected = 0.5 * 5.0 / 9.0;
+    let (mean, error) = estimate(4000, || integrator.color_at(&w, &r, &mut rng).r);
+    assert!(
+        (mean - expected).abs() < 4.0 * error,
+        "{mean} vs {expected} ± {error}"
+    );
+}
+
+#[test]
+fn test_bidirectional_through_specular() {
+    // The camera sees the lamp-lit floor in a mirror, and through a glass ball.
+    let mut w = lamp_over_floor();
+    let mut mirror = Sphere::new(Point3::new(2.0, 1.0, 0.0), 1.0);
+    mirror.material.diffuse = 0.0;
+    mirror.material.specular = 0.0;
+    mirror.material.reflective = 1.0;
+    let mut glass = Sphere::new(Point3::new(-2.0, 1.0, 0.0), 1.0);
+    glass.material.diffuse = 0.0;
+    glass.material.specular = 0.0;
+    glass.material.transparency = 1.0;
+    glass.material.refractive_index = 1.5;
+    w.objects.extend([mirror, glass]);
+    let bidirectional = Integrator::Bidirectional {
+        max_depth: 4,
+        heuristic: MisHeuristic::Power,
+    };
+    let path = Integrator::Path {
+        max_depth: 4,
+        heuristic: MisHeuristic::Power,
+    };
+    let mut rng = StdRng::seed_from_u64(11);
+
+    for x in [2.0, -2.0] {
+        let r = Ray::new(
+            Point3::new(x, 1.0, -5.0),
+            Vector3::new(0.0, -0.3, 1.0).normalize(),
+        );
+        let (expected, expected_error) = estimate(20000, || path.color_at(&w, &r, &mut rng).r);
+        let (mean, error) = estimate(20000, || bidirectional.color_at(&w, &r, &mut rng).r);
+        assert!(mean > 0.01);
+        let tolerance = 4.0 * (error.powi(2) + expected_error.powi(2)).sqrt();
+        assert!(
+            (mean - expected).abs() < tolerance,
+            "{mean} vs {expected} ± {tolerance}"
+        );
+    }
+}
+
+#[test]
+fn test_light_tracing() {
+    // Light traced straight to a pinhole camera, splatted over the image, adds up with
+    // the camera's own samples to the image a path tracer sees.
+    let w = lamp_over_floor();
+    // The floor fills the view, the lamp just outside it.
+    let mut camera = Camera::new(16, 12, PI / 3.0);
+    camera.transform = Isometry3::look_at_rh(
+        &Point3::new(0.0, 2.0, -5.0),
+        &Point3::origin(),
+        &Vector3::y(),
+    );
+    let bidirectional = Integrator::Bidirectional {
+        max_depth: 2,
+        heuristic: MisHeuristic::Balance,
+    };
+    let path = Integrator::Path {
+        max_depth: 2,
+        heuristic: MisHeuristic::Balance,
+    };
+    let mut rng = StdRng::seed_from_u64(3);
+
+    // Each sample, from a random point of the image, with its splats estimates the
+    // image's mean.
+    let mut splatted = 0;
+    let mut image_mean = |integrator: &Integrator, rng: &mut StdRng| {
+        let (x, y) = (rng.gen::<f32>() * 16.0, rng.gen::<f32>() * 12.0);
+        let r = camera.ray_through(x, y);
+        let mut splats = Vec::new();
+        let color = integrator.sample(&camera, &w, &r, rng, &mut splats);
+        splatted += splats.len();
+        splats
+            .iter()
+            .fold(color.r, |sum, &(_, _, splat)| sum + splat.r)
+    };
+    let (expected, expected_error) = estimate(20000, || image_mean(&path, &mut rng));
+    let (mean, error) = estimate(20000, || image_mean(&bidirectional, &mut rng));
+    assert!(splatted > 1000);
+    let tolerance = 4.0 * (error.powi(2) + expected_error.powi(2)).sqrt();
+    assert!(
+        (mean - expected).abs() < tolerance,
+        "{mean} vs {expected} ± {tolerance}"
+    );
+}
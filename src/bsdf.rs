@@ -0,0 +1,277 @@
+use std::f32::consts::PI;
+
+#[cfg(test)]
+use approx::assert_relative_eq;
+use rand::Rng;
+use rapier3d::na::Vector3;
+#[cfg(test)]
+use rapier3d::{na::Point3, prelude::Ray};
+
+use crate::{
+    computation::Computation,
+    intersections::{reflect, refract, schlick},
+    materials::Material,
+    ray_rgb::RayRgb,
+};
+#[cfg(test)]
+use crate::{
+    intersections::{intersection, prepare_computations},
+    sphere::Sphere,
+};
+
+// Energy-conserving modified Phong BSDF built from a Phong `Material`: a Lambertian
+// lobe weighted by `diffuse` and a glossy lobe around the mirror direction weighted
+// by `specular` and sharpened by `shininess`. At a hit, `Bsdf::at` adds the perfect
+// mirror and refraction lobes of reflective and transparent materials.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bsdf {
+    pub diffuse: RayRgb,
+    pub specular: f32,
+    pub shininess: f32,
+    pub normal: Vector3<f32>,
+    // Fractions of the light arriving along `reflectv`, and along `refractv`, that
+    // leave towards the eye.
+    pub reflect: f32,
+    pub refract: f32,
+    pub reflectv: Vector3<f32>,
+    pub refractv: Vector3<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BsdfSample {
+    pub wi: Vector3<f32>,
+    pub f: RayRgb,
+    pub pdf: f32,
+    // Whether `wi` came from a mirror or refraction lobe. Such a sample's `f` is the
+    // fraction of light it carries, with no cosine, and `pdf` the chance of choosing
+    // that lobe. No other strategy can find the same direction.
+    pub specular: bool,
+}
+
+impl Bsdf {
+    pub fn new(material: &Material, color: RayRgb, normal: Vector3<f32>) -> Self {
+        let diffuse = color * material.diffuse;
+        let total = diffuse.r.max(diffuse.g).max(diffuse.b) + material.specular;
+        let scale = if total > 1.0 { 1.0 / total } else { 1.0 };
+        Self {
+            diffuse: diffuse * scale,
+            specular: material.specular * scale,
+            shininess: material.shininess as f32,
+            normal,
+            reflect: 0.0,
+            refract: 0.0,
+            reflectv: Vector3::zeros(),
+            refractv: Vector3::zeros(),
+        }
+    }
+
+    // The BSDF seen along `comps.eyev`, with mirror and refraction lobes weighted as
+    // `shade_hit` weighs reflected and refracted light. The Phong lobes keep what those
+    // leave, and light that cannot refract is reflected instead.
+    pub fn at(comps: &Computation, color: RayRgb) -> Self {
+        let material = comps.object.material();
+        let mut bsdf = Self::new(material, color, comps.shading_normalv);
+        let (mut reflect_weight, mut refract_weight) = (material.reflective, material.transparency);
+        if material.reflective > 0.0 && material.transparency > 0.0 {
+            let reflectance = schlick(comps);
+            reflect_weight *= reflectance;
+            refract_weight *= 1.0 - reflectance;
+        }
+        let total = reflect_weight + refract_weight;
+        if total <= 0.0 {
+            return bsdf;
+        }
+        if total > 1.0 {
+            reflect_weight /= total;
+            refract_weight /= total;
+        }
+        let phong = (1.0 - reflect_weight - refract_weight).max(0.0);
+        bsdf.diffuse = bsdf.diffuse * phong;
+        bsdf.specular *= phong;
+        bsdf.reflectv = comps.reflectv;
+        match refract(comps, &-comps.eyev) {
+            Some(refractv) => {
+                bsdf.reflect = reflect_weight;
+                bsdf.refract = refract_weight;
+                bsdf.refractv = refractv;
+            }
+            None => bsdf.reflect = reflect_weight + refract_weight,
+        }
+        bsdf
+    }
+
+    pub fn eval(&self, wo: &Vector3<f32>, wi: &Vector3<f32>) -> RayRgb {
+        if wo.dot(&self.normal) <= 0.0 || wi.dot(&self.normal) <= 0.0 {
+            return RayRgb::black();
+        }
+        let mirror = reflect(&-wo, &self.normal);
+        let cos_alpha = mirror.dot(wi).max(0.0);
+        let glossy =
+            self.specular * (self.shininess + 2.0) / (2.0 * PI) * cos_alpha.powf(self.shininess);
+        self.diffuse * (1.0 / PI) + glossy
+    }
+
+    pub fn pdf(&self, wo: &Vector3<f32>, wi: &Vector3<f32>) -> f32 {
+        let cos_theta = wi.dot(&self.normal);
+        if wo.dot(&self.normal) <= 0.0 || cos_theta <= 0.0 {
+            return 0.0;
+        }
+        let diffuse_weight = self.diffuse_weight();
+        let mirror = reflect(&-wo, &self.normal);
+        let cos_alpha = mirror.dot(wi).max(0.0);
+        let glossy_pdf = (self.shininess + 1.0) / (2.0 * PI) * cos_alpha.powf(self.shininess);
+        let phong_pdf = diffuse_weight * cos_theta / PI + (1.0 - diffuse_weight) * glossy_pdf;
+        phong_pdf * self.phong_weight()
+    }
+
+    pub fn sample<R: Rng>(&self, wo: &Vector3<f32>, rng: &mut R) -> Option<BsdfSample> {
+        if self.reflect + self.refract > 0.0 {
+            let total = luminance(&self.diffuse) + self.specular + self.reflect + self.refract;
+            let u = rng.gen::<f32>() * total;
+            if u < self.reflect {
+                return Some(self.specular_sample(self.reflectv, self.reflect, total));
+            }
+            if u < self.reflect + self.refract {
+                return Some(self.specular_sample(self.refractv, self.refract, total));
+            }
+        }
+        if self.diffuse_weight() + self.specular <= 0.0 || wo.dot(&self.normal) <= 0.0 {
+            return None;
+        }
+        let (u1, u2): (f32, f32) = (rng.gen(), rng.gen());
+        let wi = if rng.gen::<f32>() < self.diffuse_weight() {
+            let r = u1.sqrt();
+            let phi = 2.0 * PI * u2;
+            to_world(
+                &self.normal,
+                Vector3::new(r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt()),
+            )
+        } else {
+            let cos_alpha = u1.powf(1.0 / (self.shininess + 1.0));
+            let sin_alpha = (1.0 - cos_alpha * cos_alpha).max(0.0).sqrt();
+            let phi = 2.0 * PI * u2;
+            let mirror = reflect(&-wo, &self.normal);
+            to_world(
+                &mirror,
+                Vector3::new(sin_alpha * phi.cos(), sin_alpha * phi.sin(), cos_alpha),
+            )
+        };
+
+        let pdf = self.pdf(wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, &wi),
+            pdf,
+            specular: false,
+        })
+    }
+
+    fn specular_sample(&self, wi: Vector3<f32>, weight: f32, total: f32) -> BsdfSample {
+        BsdfSample {
+            wi,
+            f: RayRgb::white() * weight,
+            pdf: weight / total,
+            specular: true,
+        }
+    }
+
+    // Probability of sampling the Phong lobes rather than the mirror or refraction one.
+    fn phong_weight(&self) -> f32 {
+        let phong = luminance(&self.diffuse) + self.specular;
+        if phong <= 0.0 {
+            return 0.0;
+        }
+        phong / (phong + self.reflect + self.refract)
+    }
+
+    // Probability of sampling the diffuse lobe rather than the glossy one.
+    fn diffuse_weight(&self) -> f32 {
+        let d = luminance(&self.diffuse);
+        if d + self.specular <= 0.0 {
+            return 0.0;
+        }
+        d / (d + self.specular)
+    }
+}
+
+pub fn luminance(c: &RayRgb) -> f32 {
+    0.2126 * c.r + 0.7152 * c.g + 0.0722 * c.b
+}
+
+// Orthonormal basis (tangent, bitangent) perpendicular to `n`.
+pub fn orthonormal_basis(n: &Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let sign = 1.0_f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (
+        Vector3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vector3::new(b, sign + n.y * n.y * a, -n.y),
+    )
+}
+
+// Rotates `v`, given in a frame where +Z is `n`, into world space.
+pub fn to_world(n: &Vector3<f32>, v: Vector3<f32>) -> Vector3<f32> {
+    let (t, b) = orthonormal_basis(n);
+    (t * v.x + b * v.y + n * v.z).normalize()
+}
+
+#[test]
+fn test_bsdf_sample_matches_pdf() {
+    let m = Material {
+        diffuse: 0.6,
+        specular: 0.3,
+        shininess: 20,
+        ..Default::default()
+    };
+    let n = Vector3::new(0.0, 1.0, 0.0);
+    let bsdf = Bsdf::new(&m, RayRgb::white(), n);
+    let wo = Vector3::new(1.0, 1.0, 0.0).normalize();
+
+    let mut rng = rand::thread_rng();
+    for _ in 0..100 {
+        if let Some(s) = bsdf.sample(&wo, &mut rng) {
+            assert!(s.wi.dot(&n) > 0.0);
+            assert_relative_eq!(s.pdf, bsdf.pdf(&wo, &s.wi), epsilon = 0.0001);
+        }
+    }
+
+    assert_eq!(bsdf.eval(&wo, &-n), RayRgb::black());
+    assert_relative_eq!(bsdf.pdf(&wo, &-n), 0.0);
+}
+
+#[test]
+fn test_bsdf_specular_lobes() {
+    let mut mirror = Sphere::default();
+    mirror.material.reflective = 1.0;
+    let r = Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+    let i = intersection(4.0, &mirror);
+    let comps = prepare_computations(&i, &r);
+    let bsdf = Bsdf::at(&comps, RayRgb::white());
+    assert_eq!(bsdf.eval(&comps.eyev, &comps.eyev), RayRgb::black());
+    let mut rng = rand::thread_rng();
+    let s = bsdf.sample(&comps.eyev, &mut rng).unwrap();
+    assert!(s.specular);
+    assert_relative_eq!(s.wi, Vector3::new(0.0, 0.0, -1.0));
+    assert_relative_eq!(s.f.r / s.pdf, 1.0);
+
+    // Glass both reflects and transmits, the latter into the sphere.
+    let mut glass = Sphere::default();
+    glass.material.diffuse = 0.0;
+    glass.material.specular = 0.0;
+    glass.material.reflective = 1.0;
+    glass.material.transparency = 1.0;
+    glass.material.refractive_index = 1.5;
+    let i = intersection(4.0, &glass);
+    let comps = prepare_computations(&i, &r);
+    let bsdf = Bsdf::at(&comps, RayRgb::white());
+    assert_relative_eq!(bsdf.reflect, schlick(&comps));
+    assert_relative_eq!(bsdf.reflect + bsdf.refract, 1.0);
+    let samples: Vec<_> = (0..100)
+        .map(|_| bsdf.sample(&comps.eyev, &mut rng).unwrap())
+        .collect();
+    assert!(samples.iter().all(|s| s.specular));
+    assert!(samples.iter().any(|s| s.wi.dot(&comps.normalv) < 0.0));
+}
@@ -0,0 +1,215 @@
+#[cfg(test)]
+use approx::assert_relative_eq;
+#[cfg(test)]
+use image::DynamicImage;
+use rapier3d::na::{Point3, Vector3};
+
+#[cfg(test)]
+use crate::texture::{ImageTexture, UvMapping};
+use crate::{
+    bsdf::{luminance, orthonormal_basis},
+    pattern::Pattern,
+    ray_rgb::RayRgb,
+    texture::Texture,
+};
+
+// Step used to take finite differences of a height field, in object units.
+const HEIGHT_DELTA: f32 = 0.001;
+
+// Where a bump map reads its heights from: the luminance of an image or a pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeightSource {
+    Image(Texture),
+    Pattern(Pattern),
+}
+
+impl HeightSource {
+    fn height_at(&self, point: &Point3<f32>, center: &Point3<f32>, normal: &Vector3<f32>) -> f32 {
+        let color: RayRgb = match self {
+            HeightSource::Image(texture) => texture.color_at(&Point3::from(point - center), normal),
+            HeightSource::Pattern(pattern) => pattern.color_at(point),
+        };
+        luminance(&color)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NormalMap {
+    // An RGB image of normals in the surface's tangent frame, with X along the tangent,
+    // Y along the bitangent and Z along the normal. `strength` scales the tilt.
+    Tangent { texture: Texture, strength: f32 },
+    // Tilts the normal down the slope of a height field.
+    Bump { height: HeightSource, strength: f32 },
+}
+
+impl NormalMap {
+    // Perturbs `normal` at `point`, both in object space, with `tangent` completing the
+    // surface frame. Images are looked up relative to the object's `center` as in
+    // `Texture::color_at`, patterns at the object space point itself.
+    pub fn perturb(
+        &self,
+        point: &Point3<f32>,
+        center: &Point3<f32>,
+        normal: &Vector3<f32>,
+        tangent: &Vector3<f32>,
+    ) -> Vector3<f32> {
+        let bitangent = normal.cross(tangent).normalize();
+        let tangent = bitangent.cross(normal).normalize();
+        let perturbed = match self {
+            NormalMap::Tangent { texture, strength } => {
+                let c = texture.color_at(&Point3::from(point - center), normal);
+                tangent * ((2.0 * c.r - 1.0) * strength)
+                    + bitangent * ((2.0 * c.g - 1.0) * strength)
+                    + normal * (2.0 * c.b - 1.0)
+            }
+            NormalMap::Bump { height, strength } => {
+                let h = height.height_at(point, center, normal);
+                let hu = height.height_at(&(point + tangent * HEIGHT_DELTA), center, normal);
+                let hv = height.height_at(&(point + bitangent * HEIGHT_DELTA), center, normal);
+                let dhdu = (hu - h) / HEIGHT_DELTA;
+                let dhdv = (hv - h) / HEIGHT_DELTA;
+                normal - (tangent * dhdu + bitangent * dhdv) * *strength
+            }
+        };
+        if perturbed.magnitude_squared() == 0.0 {
+            return *normal;
+        }
+        perturbed.normalize()
+    }
+}
+
+// Per-vertex tangents of a mesh with vertex `points`, texture coordinates `uvs` and
+// `normals`, indexed by `triangles`: the direction in which u grows across each
+// triangle, averaged around each vertex and made perpendicular to its normal. Vertices
+// whose triangles have no usable UVs get any tangent perpendicular to the normal. There
+// is no mesh shape yet; these are the frames one would pass to `NormalMap::perturb`.
+pub fn mesh_tangents(
+    points: &[Point3<f32>],
+    uvs: &[(f32, f32)],
+    normals: &[Vector3<f32>],
+    triangles: &[[usize; 3]],
+) -> Vec<Vector3<f32>> {
+    let mut sums = vec![Vector3::zeros(); points.len()];
+    for &[a, b, c] in triangles {
+        let (e1, e2) = (points[b] - points[a], points[c] - points[a]);
+        let (du1, dv1) = (uvs[b].0 - uvs[a].0, uvs[b].1 - uvs[a].1);
+        let (du2, dv2) = (uvs[c].0 - uvs[a].0, uvs[c].1 - uvs[a].1);
+        let determinant = du1 * dv2 - du2 * dv1;
+        if determinant == 0.0 {
+            continue;
+        }
+        let tangent = (e1 * dv2 - e2 * dv1) / determinant;
+        for i in [a, b, c] {
+            sums[i] += tangent;
+        }
+    }
+    sums.iter()
+        .zip(normals)
+        .map(|(t, n)| {
+            let t = t - n * n.dot(t);
+            if t.magnitude_squared() > 0.0 {
+                t.normalize()
+            } else {
+                orthonormal_basis(n).0
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn solid_texture(c: [u8; 3]) -> Texture {
+    let image = image::RgbImage::from_pixel(1, 1, image::Rgb(c));
+    Texture::new(
+        ImageTexture::from_image(&DynamicImage::ImageRgb8(image)),
+        UvMapping::Planar,
+    )
+}
+
+#[test]
+fn test_tangent_space_normal_map() {
+    let n = Vector3::new(0.0, 1.0, 0.0);
+    let t = Vector3::new(1.0, 0.0, 0.0);
+    let p = Point3::new(0.2, 1.0, 0.3);
+
+    let flat = NormalMap::Tangent {
+        texture: solid_texture([128, 128, 255]),
+        strength: 1.0,
+    };
+    assert_relative_eq!(
+        flat.perturb(&p, &Point3::origin(), &n, &t),
+        n,
+        epsilon = 0.01
+    );
+
+    let tilted = NormalMap::Tangent {
+        texture: solid_texture([255, 128, 128]),
+        strength: 1.0,
+    };
+    assert_relative_eq!(
+        tilted.perturb(&p, &Point3::origin(), &n, &t),
+        t,
+        epsilon = 0.01
+    );
+}
+
+#[test]
+fn test_bump_map() {
+    let n = Vector3::new(0.0, 0.0, -1.0);
+    let t = Vector3::new(1.0, 0.0, 0.0);
+    let bump = NormalMap::Bump {
+        height: HeightSource::Image(solid_texture([90, 90, 90])),
+        strength: 5.0,
+    };
+    assert_relative_eq!(
+        bump.perturb(&Point3::new(0.0, 0.0, -1.0), &Point3::origin(), &n, &t),
+        n,
+        epsilon = 0.0001
+    );
+
+    let mut ramp = image::RgbImage::new(2, 1);
+    ramp.put_pixel(1, 0, image::Rgb([255, 255, 255]));
+    let ramp = Texture::new(
+        ImageTexture::from_image(&DynamicImage::ImageRgb8(ramp)),
+        UvMapping::Planar,
+    );
+    let bump = NormalMap::Bump {
+        height: HeightSource::Image(ramp),
+        strength: 0.5,
+    };
+    let n = Vector3::new(0.0, 1.0, 0.0);
+    let half = 2.0_f32.sqrt() / 2.0;
+    assert_relative_eq!(
+        bump.perturb(&Point3::new(0.5, 0.0, 0.5), &Point3::origin(), &n, &t),
+        Vector3::new(-half, half, 0.0),
+        epsilon = 0.001
+    );
+}
+
+#[test]
+fn test_mesh_tangents() {
+    // A unit square in the XZ plane facing up, with u running along X.
+    let points = [
+        Point3::new(0.0, 0.0, 0.0),
+        Point3::new(1.0, 0.0, 0.0),
+        Point3::new(1.0, 0.0, 1.0),
+        Point3::new(0.0, 0.0, 1.0),
+    ];
+    let normals = [Vector3::y(); 4];
+    let triangles = [[0, 1, 2], [0, 2, 3]];
+    let uvs = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+    for t in mesh_tangents(&points, &uvs, &normals, &triangles) {
+        assert_relative_eq!(t, Vector3::x(), epsilon = 0.0001);
+    }
+
+    // Turning the image a quarter turn turns the tangent with it.
+    let uvs = [(0.0, 0.0), (0.0, 1.0), (-1.0, 1.0), (-1.0, 0.0)];
+    for t in mesh_tangents(&points, &uvs, &normals, &triangles) {
+        assert_relative_eq!(t, -Vector3::z(), epsilon = 0.0001);
+    }
+
+    // Without usable UVs the tangent still lies in the surface.
+    let tangents = mesh_tangents(&points, &[(0.0, 0.0); 4], &normals, &triangles);
+    assert!(tangents
+        .iter()
+        .all(|t| t.dot(&Vector3::y()).abs() < 0.0001 && (t.magnitude() - 1.0).abs() < 0.0001));
+}
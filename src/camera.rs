@@ -1,25 +1,81 @@
 #[cfg(test)]
 use approx::assert_relative_eq;
 use image::{ImageBuffer, Rgb};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rapier3d::{
     na::{Isometry3, Point3, Rotation3, Translation3, UnitQuaternion, Vector3},
     prelude::*,
 };
-use std::f32::consts::PI;
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    sync::{Arc, Mutex, OnceLock},
+};
 
 use crate::{
+    background::Background,
+    filter::Filter,
+    integrator::Integrator,
     ray_rgb::RayRgb,
-    world::{color_at, World},
+    sampler::{Sampler, SamplerKind},
+    world::{hit_world, World},
 };
 use std::env;
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Projection {
+    // A pinhole at the origin looking down -Z through an image plane one unit away.
+    #[default]
+    Perspective,
+    // Parallel rays down -Z from a view `width` world units across, its height following
+    // the image's aspect ratio.
+    Orthographic {
+        width: f32,
+    },
+    // Longitude across and latitude down the image, covering every direction.
+    Equirectangular,
+    // A circular image, inscribed in the frame, covering `fov` radians across.
+    Fisheye {
+        fov: f32,
+        mapping: FisheyeMapping,
+    },
+    // Six square faces side by side in +X, -X, +Y, -Y, +Z, -Z order, each looking out
+    // along its axis with the usual cube map orientation. In a frame that is not six
+    // times as wide as it is high, the strip of faces is centered with nothing around it.
+    Cubemap,
+}
+
+// How a fisheye's distance from the image center relates to the angle off its axis.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FisheyeMapping {
+    // Proportional to the angle.
+    #[default]
+    Equidistant,
+    // Preserves solid angle, so every pixel covers the same part of the sphere.
+    Equisolid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Camera {
     pub hsize: u32,
     pub vsize: u32,
     pub field_of_view: f32,
     pub transform: Isometry3<f32>,
+    pub projection: Projection,
     pub pixel_size: f32,
     pub half_width: f32,
     pub half_height: f32,
+    // Radius of the lens. Zero gives a pinhole camera with everything in focus.
+    pub aperture: f32,
+    // Distance along the view direction at which objects are in focus.
+    pub focal_distance: f32,
+    // Number of straight blades shaping the aperture into a polygon, or zero for a
+    // round aperture. Shapes the bokeh of out of focus highlights.
+    pub aperture_blades: u32,
+    // Distance of this eye from the center of a stereo pair, positive towards the
+    // image's left edge, and the distance at which both eyes' views line up.
+    pub eye_offset: f32,
+    pub convergence: f32,
 }
 
 impl Camera {
@@ -40,43 +96,667 @@ impl Camera {
             vsize,
             field_of_view,
             transform: Isometry3::identity(),
+            projection: Projection::Perspective,
             pixel_size: (half_width * 2.0) / hsize as f32,
             half_width,
             half_height,
+            aperture: 0.0,
+            focal_distance: 1.0,
+            aperture_blades: 0,
+            eye_offset: 0.0,
+            convergence: 1.0,
         }
     }
 
     pub fn ray_for_pixel(&self, x: u32, y: u32) -> Ray {
-        let x = x as f32;
-        let y = y as f32;
-        let xoffset = (x + 0.5) * self.pixel_size;
-        let yoffset = (y + 0.5) * self.pixel_size;
+        self.ray_through(x as f32 + 0.5, y as f32 + 0.5)
+    }
+
+    // Ray through the point (x, y) of the image, measured in pixels from its top left
+    // corner.
+    pub fn ray_through(&self, x: f32, y: f32) -> Ray {
+        let (origin, direction) = self.camera_space_ray(x, y);
+        let origin = self.transform.inverse_transform_point(&origin);
+        let direction = self
+            .transform
+            .inverse_transform_vector(&direction)
+            .normalize();
+        Ray::new(origin, direction)
+    }
+
+    // Origin and direction of the pinhole ray through (x, y) before the camera's
+    // transform is applied.
+    fn camera_space_ray(&self, x: f32, y: f32) -> (Point3<f32>, Vector3<f32>) {
+        let xoffset = x * self.pixel_size;
+        let yoffset = y * self.pixel_size;
         let world_x = self.half_width - xoffset;
         let world_y = self.half_height - yoffset;
 
-        let pixel = self
-            .transform
-            .inverse_transform_point(&Point3::new(world_x, world_y, -1.0));
+        let direction = match self.projection {
+            Projection::Perspective => Vector3::new(world_x, world_y, -1.0),
+            // Stereo eyes shift sideways and shear their parallel rays so that the two
+            // views meet on the convergence plane.
+            Projection::Orthographic { width } => {
+                let scale = width / (2.0 * self.half_width);
+                return (
+                    Point3::new(world_x * scale + self.eye_offset, world_y * scale, 0.0),
+                    Vector3::new(-self.eye_offset / self.convergence, 0.0, -1.0),
+                );
+            }
+            _ => self.panorama_direction(x, y),
+        };
+        if self.eye_offset == 0.0 {
+            return (Point3::origin(), direction);
+        }
+
+        // Perspective eyes shift sideways and aim at the same point on the convergence
+        // plane. Panoramic eyes sit on a circle, offset across each ray's horizontal
+        // direction, for omni-directional stereo.
+        let (eye, target) = match self.projection {
+            Projection::Perspective => (
+                Point3::new(self.eye_offset, 0.0, 0.0),
+                Point3::from(direction * self.convergence),
+            ),
+            _ => {
+                let d = direction.normalize();
+                let side = Vector3::new(-d.z, 0.0, d.x);
+                let side = if side.magnitude_squared() > 0.0 {
+                    side.normalize()
+                } else {
+                    side
+                };
+                (
+                    Point3::from(side * self.eye_offset),
+                    Point3::from(d * self.convergence),
+                )
+            }
+        };
+        (eye, target - eye)
+    }
+
+    // Panoramic directions keep the perspective convention that the image's left edge
+    // lies towards +X.
+    fn panorama_direction(&self, x: f32, y: f32) -> Vector3<f32> {
+        let (width, height) = (self.hsize as f32, self.vsize as f32);
+        match self.projection {
+            Projection::Fisheye { fov, mapping } => {
+                let (a, b, r) = self.fisheye_coords(x, y);
+                let theta = match mapping {
+                    FisheyeMapping::Equidistant => r * fov / 2.0,
+                    FisheyeMapping::Equisolid => {
+                        2.0 * (r * (fov / 4.0).sin()).clamp(-1.0, 1.0).asin()
+                    }
+                };
+                if r == 0.0 {
+                    return -Vector3::z();
+                }
+                Vector3::new(a / r * theta.sin(), b / r * theta.sin(), -theta.cos())
+            }
+            Projection::Cubemap => {
+                let (face_size, left, top) = self.cubemap_layout();
+                let (x, y) = (x - left, y - top);
+                let face = ((x / face_size).floor() as i32).clamp(0, 5);
+                let s = 2.0 * (x - face as f32 * face_size) / face_size - 1.0;
+                let t = 2.0 * y / face_size - 1.0;
+                match face {
+                    0 => Vector3::new(1.0, -t, -s),
+                    1 => Vector3::new(-1.0, -t, s),
+                    2 => Vector3::new(s, 1.0, t),
+                    3 => Vector3::new(s, -1.0, -t),
+                    4 => Vector3::new(s, -t, 1.0),
+                    _ => Vector3::new(-s, -t, -1.0),
+                }
+            }
+            _ => {
+                let phi = (x / width - 0.5) * 2.0 * PI;
+                let theta = y / height * PI;
+                Vector3::new(
+                    -theta.sin() * phi.sin(),
+                    theta.cos(),
+                    -theta.sin() * phi.cos(),
+                )
+            }
+        }
+    }
+
+    // Position of image point (x, y) relative to the fisheye circle, as (left, up) in
+    // units of its radius, together with the distance from its center.
+    fn fisheye_coords(&self, x: f32, y: f32) -> (f32, f32, f32) {
+        let radius = self.hsize.min(self.vsize) as f32 / 2.0;
+        let a = (self.hsize as f32 / 2.0 - x) / radius;
+        let b = (self.vsize as f32 / 2.0 - y) / radius;
+        (a, b, (a * a + b * b).sqrt())
+    }
+
+    // Side of each cube map face, and the top left corner of the strip of faces.
+    fn cubemap_layout(&self) -> (f32, f32, f32) {
+        let (width, height) = (self.hsize as f32, self.vsize as f32);
+        let face_size = (width / 6.0).min(height);
+        (
+            face_size,
+            (width - 6.0 * face_size) / 2.0,
+            (height - face_size) / 2.0,
+        )
+    }
+
+    // Whether image point (x, y) shows the scene at all, which is not the case outside
+    // a fisheye's circle or a cube map's strip of faces.
+    pub fn sees(&self, x: f32, y: f32) -> bool {
+        match self.projection {
+            Projection::Fisheye { .. } => self.fisheye_coords(x, y).2 <= 1.0,
+            Projection::Cubemap => {
+                let (face_size, left, top) = self.cubemap_layout();
+                (left..left + 6.0 * face_size).contains(&x) && (top..top + face_size).contains(&y)
+            }
+            _ => true,
+        }
+    }
+
+    // Ray through image point (x, y) from a random point on the lens, aimed so that
+    // all rays through the same image point meet on the focal plane. Only perspective
+    // cameras have a lens.
+    pub fn sample_ray<R: Rng>(&self, x: f32, y: f32, rng: &mut R) -> Ray {
+        if self.aperture <= 0.0 || self.projection != Projection::Perspective {
+            return self.ray_through(x, y);
+        }
+        let (eye, direction) = self.camera_space_ray(x, y);
+        let focus = eye + direction * (self.focal_distance / -direction.z);
+        let (lens_x, lens_y) = self.lens_point(rng);
+
+        let focus = self.transform.inverse_transform_point(&focus);
         let origin = self
             .transform
-            .inverse_transform_point(&Point3::new(0.0, 0.0, 0.0));
-        let direction = (pixel - origin).normalize();
-        Ray::new(origin, direction)
+            .inverse_transform_point(&(eye + Vector3::new(lens_x, lens_y, 0.0)));
+        Ray::new(origin, (focus - origin).normalize())
+    }
+
+    // Whether every ray starts from one point, so that light can be traced straight to
+    // the camera: a perspective camera with a pinhole and a single eye.
+    pub fn is_pinhole(&self) -> bool {
+        self.projection == Projection::Perspective && self.aperture <= 0.0 && self.eye_offset == 0.0
+    }
+
+    // The image point whose pinhole ray runs along world direction `dir`, if the image
+    // shows that direction.
+    pub fn image_point(&self, dir: &Vector3<f32>) -> Option<(f32, f32)> {
+        let d = self.transform.transform_vector(dir);
+        if d.z >= 0.0 {
+            return None;
+        }
+        let x = (self.half_width + d.x / d.z) / self.pixel_size;
+        let y = (self.half_height + d.y / d.z) / self.pixel_size;
+        ((0.0..self.hsize as f32).contains(&x) && (0.0..self.vsize as f32).contains(&y))
+            .then_some((x, y))
+    }
+
+    // Density, per unit solid angle, with which the pinhole ray through a uniformly
+    // random point of the image runs along world direction `dir`.
+    pub fn pdf_direction(&self, dir: &Vector3<f32>) -> f32 {
+        if self.image_point(dir).is_none() {
+            return 0.0;
+        }
+        let cos_theta = -self.transform.transform_vector(dir).normalize().z;
+        let area = 4.0 * self.half_width * self.half_height;
+        1.0 / (area * cos_theta.powi(3))
+    }
+
+    // Uniformly samples the aperture, a disk or a regular polygon with a vertex on +X.
+    fn lens_point<R: Rng>(&self, rng: &mut R) -> (f32, f32) {
+        if self.aperture_blades < 3 {
+            let r = self.aperture * rng.gen::<f32>().sqrt();
+            let theta = 2.0 * PI * rng.gen::<f32>();
+            return (r * theta.cos(), r * theta.sin());
+        }
+        // Pick one of the triangles fanning out from the center, then a point in it.
+        let blades = self.aperture_blades as f32;
+        let blade = rng.gen_range(0..self.aperture_blades) as f32;
+        let (a0, a1) = (2.0 * PI * blade / blades, 2.0 * PI * (blade + 1.0) / blades);
+        let (mut u, mut v) = (rng.gen::<f32>(), rng.gen::<f32>());
+        if u + v > 1.0 {
+            (u, v) = (1.0 - u, 1.0 - v);
+        }
+        (
+            self.aperture * (u * a0.cos() + v * a1.cos()),
+            self.aperture * (u * a0.sin() + v * a1.sin()),
+        )
+    }
+}
+
+// Where within a pixel its samples are placed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PixelSampling {
+    // Every sample goes through the pixel center, so extra samples only average the
+    // integrator's noise.
+    #[default]
+    Center,
+    // Cell centers of a regular grid over the pixel.
+    Grid,
+    // One random point in each cell of the grid.
+    Jittered,
+    // Uniformly random points.
+    Random,
+}
+
+impl PixelSampling {
+    // Offset within the pixel, each coordinate in [0, 1), of sample `index` of `count`.
+    // Indices past `count` start over from the first cell.
+    pub fn offset<R: Rng>(&self, index: u32, count: u32, rng: &mut R) -> (f32, f32) {
+        let count = count.max(1);
+        let index = index % count;
+        let columns = (count as f32).sqrt().ceil() as u32;
+        let rows = count.div_ceil(columns);
+        let (column, row) = ((index % columns) as f32, (index / columns) as f32);
+        match self {
+            PixelSampling::Center => (0.5, 0.5),
+            PixelSampling::Grid => ((column + 0.5) / columns as f32, (row + 0.5) / rows as f32),
+            PixelSampling::Jittered => (
+                (column + rng.gen::<f32>()) / columns as f32,
+                (row + rng.gen::<f32>()) / rows as f32,
+            ),
+            PixelSampling::Random => (rng.gen(), rng.gen()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderSettings {
+    pub integrator: Integrator,
+    pub samples_per_pixel: u32,
+    pub pixel_sampling: PixelSampling,
+    // Where every sample's random numbers come from, including the pixel offsets of
+    // jittered and random pixel sampling.
+    pub sampler: SamplerKind,
+    // How samples are weighted into the pixels around them.
+    pub filter: Filter,
+    // How many threads to render with, or `None` for one per core.
+    pub threads: Option<usize>,
+    // Seeds each pixel's random numbers, so a render does not depend on how its pixels
+    // are spread across threads.
+    pub seed: u64,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            integrator: Integrator::Whitted,
+            samples_per_pixel: 1,
+            pixel_sampling: PixelSampling::Center,
+            sampler: SamplerKind::Random,
+            filter: Filter::Box { radius: 0.5 },
+            threads: None,
+            seed: 0,
+        }
     }
 }
 
 pub fn render(camera: &Camera, world: &World) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-    let mut image = image::ImageBuffer::new(camera.vsize, camera.hsize);
+    render_with(camera, world, &RenderSettings::default())
+}
+
+pub fn render_with(
+    camera: &Camera,
+    world: &World,
+    settings: &RenderSettings,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    render_region(camera, world, settings, &Region::full(camera))
+}
+
+// A rectangle of pixels within the camera's image, from its top left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn full(camera: &Camera) -> Self {
+        Self::new(0, 0, camera.hsize, camera.vsize)
+    }
+
+    // The part of the region that lies within the camera's image.
+    pub fn clamp(&self, camera: &Camera) -> Self {
+        let x = self.x.min(camera.hsize);
+        let y = self.y.min(camera.vsize);
+        Self::new(
+            x,
+            y,
+            self.width.min(camera.hsize - x),
+            self.height.min(camera.vsize - y),
+        )
+    }
+}
+
+// Renders just `region` of the camera's image, exactly as those pixels would appear in
+// a full render. The result is the size of the region, clamped to the image. Light that
+// the bidirectional integrator traces straight to the camera comes only from the
+// region's own samples, so it matches a full render on average.
+pub fn render_region(
+    camera: &Camera,
+    world: &World,
+    settings: &RenderSettings,
+    region: &Region,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let region = region.clamp(camera);
+    if region.width == 0 || region.height == 0 {
+        return ImageBuffer::new(region.width, region.height);
+    }
+    // Only filters other than the default splat samples (see `Filter::default`).
+    if settings.filter != Filter::default() {
+        return render_filtered(camera, world, settings, &region);
+    }
+
+    let mut colors = vec![RayRgb::black(); (region.width * region.height) as usize];
+    let mut splats = vec![Vec::new(); region.height as usize];
+    let render_row = |(row, (pixels, splats)): RowPair<RayRgb, Vec<Splat>>| {
+        let y = region.y + row as u32;
+        for (column, pixel) in pixels.iter_mut().enumerate() {
+            let x = region.x + column as u32;
+            let mut sampler = Sampler::new(settings.sampler, settings.seed, x, y);
+            *pixel = render_pixel(camera, world, settings, x, y, &mut sampler, &mut splats[0]);
+        }
+    };
+    for_each_row_zip(
+        settings.threads,
+        (&mut colors, region.width as usize),
+        (&mut splats, 1),
+        render_row,
+    );
+    let paths = region.width * region.height * settings.samples_per_pixel.max(1);
+    let splatted = splatted(camera, &region, &splats, paths);
+    to_image(&region, |i| colors[i] + splatted[i])
+}
+
+// Takes every pixel's samples within the filter's reach of `region` once, then gathers
+// into each pixel of the region the samples its filter covers.
+fn render_filtered(
+    camera: &Camera,
+    world: &World,
+    settings: &RenderSettings,
+    region: &Region,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let filter = settings.filter;
+    let margin = (filter.radius() - 0.5).ceil().max(0.0) as u32;
+    let (x0, y0) = (
+        region.x.saturating_sub(margin),
+        region.y.saturating_sub(margin),
+    );
+    let covered = Region::new(
+        x0,
+        y0,
+        region.x + region.width + margin - x0,
+        region.y + region.height + margin - y0,
+    )
+    .clamp(camera);
+
+    let mut samples = vec![Vec::new(); (covered.width * covered.height) as usize];
+    let mut splats = vec![Vec::new(); covered.height as usize];
+    let sample_row = |(row, (pixels, splats)): RowPair<Vec<PixelSample>, Vec<Splat>>| {
+        let y = covered.y + row as u32;
+        for (column, pixel) in pixels.iter_mut().enumerate() {
+            let x = covered.x + column as u32;
+            let mut sampler = Sampler::new(settings.sampler, settings.seed, x, y);
+            *pixel = pixel_samples(camera, world, settings, x, y, &mut sampler, &mut splats[0]);
+        }
+    };
+    for_each_row_zip(
+        settings.threads,
+        (&mut samples, covered.width as usize),
+        (&mut splats, 1),
+        sample_row,
+    );
+
+    let mut colors = vec![RayRgb::black(); (region.width * region.height) as usize];
+    let gather_row = |(row, pixels): (usize, &mut [RayRgb])| {
+        let y = region.y + row as u32;
+        for (column, pixel) in pixels.iter_mut().enumerate() {
+            let x = region.x + column as u32;
+            let (color, total) = gather(&filter, &samples, &covered, x, y);
+            *pixel = if total > 0.0 {
+                color * (1.0 / total)
+            } else {
+                color
+            };
+        }
+    };
+    for_each_row(
+        settings.threads,
+        &mut colors,
+        region.width as usize,
+        gather_row,
+    );
+    let paths = covered.width * covered.height * settings.samples_per_pixel.max(1);
+    let splatted = splatted(camera, region, &splats, paths);
+    to_image(region, |i| colors[i] + splatted[i])
+}
+
+// The image of `region` whose pixels, row by row, have the colors `color(index)`.
+pub(crate) fn to_image<F>(region: &Region, color: F) -> ImageBuffer<Rgb<u8>, Vec<u8>>
+where
+    F: Fn(usize) -> RayRgb,
+{
+    ImageBuffer::from_fn(region.width, region.height, |x, y| {
+        color((y * region.width + x) as usize).to_rgb()
+    })
+}
+
+// Light traced straight to the camera, at the image point it lands on. Unlike pixel
+// samples, splats are summed into the pixel they land in rather than averaged.
+pub type Splat = PixelSample;
+
+// The splatted light landing in each pixel of `region`, row by row. Every light path
+// traced for a render estimates the light reaching the whole image, so the sum over
+// `paths` of them is scaled by the number of pixels per path.
+pub(crate) fn splatted<S: AsRef<[Splat]>>(
+    camera: &Camera,
+    region: &Region,
+    splats: &[S],
+    paths: u32,
+) -> Vec<RayRgb> {
+    let mut colors = vec![RayRgb::black(); (region.width * region.height) as usize];
+    let scale = (camera.hsize * camera.vsize) as f32 / paths.max(1) as f32;
+    for &(x, y, color) in splats.iter().flat_map(|s| s.as_ref()) {
+        let (x, y) = (x as u32, y as u32);
+        if (region.x..region.x + region.width).contains(&x)
+            && (region.y..region.y + region.height).contains(&y)
+        {
+            let index = ((y - region.y) * region.width + x - region.x) as usize;
+            colors[index] = colors[index] + color * scale;
+        }
+    }
+    colors
+}
+
+// Calls `f` with each row of `data` and its index, on `threads` threads.
+pub(crate) fn for_each_row<T: Send, F>(threads: Option<usize>, data: &mut [T], row_len: usize, f: F)
+where
+    F: Fn((usize, &mut [T])) + Sync + Send,
+{
+    in_thread_pool(threads, || match threads {
+        Some(1) => data.chunks_mut(row_len).enumerate().for_each(f),
+        _ => data.par_chunks_mut(row_len).enumerate().for_each(f),
+    });
+}
+
+// A row's index and the matching rows of two buffers.
+pub(crate) type RowPair<'a, T, U> = (usize, (&'a mut [T], &'a mut [U]));
+
+// Like `for_each_row`, for two buffers with the same number of rows, calling `f` with
+// the matching row of each.
+pub(crate) fn for_each_row_zip<T: Send, U: Send, F>(
+    threads: Option<usize>,
+    (a, a_len): (&mut [T], usize),
+    (b, b_len): (&mut [U], usize),
+    f: F,
+) where
+    F: Fn(RowPair<T, U>) + Sync + Send,
+{
+    in_thread_pool(threads, || match threads {
+        Some(1) => a
+            .chunks_mut(a_len)
+            .zip(b.chunks_mut(b_len))
+            .enumerate()
+            .for_each(f),
+        _ => a
+            .par_chunks_mut(a_len)
+            .zip(b.par_chunks_mut(b_len))
+            .enumerate()
+            .for_each(f),
+    });
+}
 
-    for y in 0..camera.vsize {
-        for x in 0..camera.hsize {
-            let ray = camera.ray_for_pixel(x, y);
-            let color = color_at(&world, &ray);
-            let pixel = image.get_pixel_mut(y, x);
-            *pixel = color.to_rgb();
+// Filter-weighted sum of the samples that reach pixel (x, y), and the sum of their
+// weights. `samples` holds the samples of each pixel of `covered`, row by row.
+pub(crate) fn gather<S: AsRef<[PixelSample]>>(
+    filter: &Filter,
+    samples: &[S],
+    covered: &Region,
+    x: u32,
+    y: u32,
+) -> (RayRgb, f32) {
+    let margin = (filter.radius() - 0.5).ceil().max(0.0) as u32;
+    let rows =
+        y.saturating_sub(margin).max(covered.y)..(y + margin + 1).min(covered.y + covered.height);
+    let columns =
+        x.saturating_sub(margin).max(covered.x)..(x + margin + 1).min(covered.x + covered.width);
+    let (center_x, center_y) = (x as f32 + 0.5, y as f32 + 0.5);
+    let (mut color, mut total) = (RayRgb::black(), 0.0);
+    for sy in rows {
+        for sx in columns.clone() {
+            let index = ((sy - covered.y) * covered.width + sx - covered.x) as usize;
+            for &(px, py, sample) in samples[index].as_ref() {
+                let weight = filter.weight(px - center_x, py - center_y);
+                color = color + sample * weight;
+                total += weight;
+            }
         }
     }
-    image
+    (color, total)
+}
+
+// Runs `f` on a pool of `threads` threads, or on rayon's global pool. Pools are kept
+// for later calls, so that renders and passes don't each spawn their own threads.
+pub(crate) fn in_thread_pool<F: FnOnce() + Send>(threads: Option<usize>, f: F) {
+    static POOLS: OnceLock<Mutex<HashMap<usize, Option<Arc<ThreadPool>>>>> = OnceLock::new();
+    let pool = match threads {
+        Some(n) if n > 1 => POOLS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .entry(n)
+            .or_insert_with(|| {
+                ThreadPoolBuilder::new()
+                    .num_threads(n)
+                    .build()
+                    .ok()
+                    .map(Arc::new)
+            })
+            .clone(),
+        _ => None,
+    };
+    match pool {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+// Random numbers for pixel (x, y), the same whichever thread renders it.
+pub fn pixel_rng(seed: u64, x: u32, y: u32) -> StdRng {
+    let pixel = ((y as u64) << 32) | x as u64;
+    StdRng::seed_from_u64(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ pixel)
+}
+
+// Average of the samples taken through pixel (x, y). Light they trace straight to the
+// camera is added to `splats`.
+pub fn render_pixel(
+    camera: &Camera,
+    world: &World,
+    settings: &RenderSettings,
+    x: u32,
+    y: u32,
+    sampler: &mut Sampler,
+    splats: &mut Vec<Splat>,
+) -> RayRgb {
+    let samples = pixel_samples(camera, world, settings, x, y, sampler, splats);
+    let sum = samples
+        .iter()
+        .fold(RayRgb::black(), |sum, &(_, _, color)| sum + color);
+    sum * (1.0 / samples.len() as f32)
+}
+
+// A sample's color and the image point it went through.
+pub type PixelSample = (f32, f32, RayRgb);
+
+// The samples taken through pixel (x, y). Light they trace straight to the camera is
+// added to `splats`.
+pub fn pixel_samples(
+    camera: &Camera,
+    world: &World,
+    settings: &RenderSettings,
+    x: u32,
+    y: u32,
+    sampler: &mut Sampler,
+    splats: &mut Vec<Splat>,
+) -> Vec<PixelSample> {
+    let count = settings.samples_per_pixel.max(1);
+    (0..count)
+        .map(|i| {
+            sampler.start_sample(i, count);
+            let (dx, dy) = settings.pixel_sampling.offset(i, count, sampler);
+            let (px, py) = (x as f32 + dx, y as f32 + dy);
+            (
+                px,
+                py,
+                sample_pixel(camera, world, settings, px, py, sampler, splats),
+            )
+        })
+        .collect()
+}
+
+// One sample through image point (x, y), black where the camera sees nothing. Light
+// the sample traces straight to the camera is added to `splats`.
+pub fn sample_pixel<R: Rng>(
+    camera: &Camera,
+    world: &World,
+    settings: &RenderSettings,
+    x: f32,
+    y: f32,
+    rng: &mut R,
+    splats: &mut Vec<Splat>,
+) -> RayRgb {
+    if !camera.sees(x, y) {
+        return RayRgb::black();
+    }
+    let ray = camera.sample_ray(x, y, rng);
+    // The integrator runs even behind a backdrop, so that every sample traces its share
+    // of light to the camera.
+    let color = settings.integrator.sample(camera, world, &ray, rng, splats);
+    backdrop(camera, world, &ray, x, y).unwrap_or(color)
+}
+
+// The background seen through image point (x, y) when its ray misses everything,
+// framed by the camera so that screen-space images line up with the render. Other
+// backgrounds are left to the integrator, which finds the miss anyway.
+fn backdrop(camera: &Camera, world: &World, ray: &Ray, x: f32, y: f32) -> Option<RayRgb> {
+    if world.environment.is_some()
+        || !matches!(world.background, Background::Screen(_))
+        || hit_world(world, ray).is_some()
+    {
+        return None;
+    }
+    let u = x / camera.hsize as f32;
+    let v = 1.0 - y / camera.vsize as f32;
+    Some(world.background.screen_color(u, v, &ray.dir))
 }
 
 #[test]
@@ -135,3 +815,299 @@ fn test_render() {
     assert_relative_eq!(p.0[1] as f32, eq_p.0[1] as f32, epsilon = 0.001);
     assert_relative_eq!(p.0[2] as f32, eq_p.0[2] as f32, epsilon = 0.001);
 }
+
+#[test]
+fn test_pixel_sampling() {
+    let mut rng = rand::thread_rng();
+    let grid: Vec<(f32, f32)> = (0..4)
+        .map(|i| PixelSampling::Grid.offset(i, 4, &mut rng))
+        .collect();
+    assert_eq!(
+        grid,
+        vec![(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)]
+    );
+    for i in 0..9 {
+        let (x, y) = PixelSampling::Jittered.offset(i, 9, &mut rng);
+        assert!((x * 3.0).floor() as u32 == i % 3 && (y * 3.0).floor() as u32 == i / 3);
+        let (x, y) = PixelSampling::Random.offset(i, 9, &mut rng);
+        assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y));
+    }
+    assert_eq!(PixelSampling::Grid.offset(5, 4, &mut rng), (0.75, 0.25));
+    for i in 0..20 {
+        let (x, y) = PixelSampling::Jittered.offset(i, 3, &mut rng);
+        assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y));
+    }
+
+    // A pixel straddling the edge of a sphere gets a blend of the sphere and the
+    // background.
+    let mut w = World::default();
+    w.objects[0].material.ambient = 1.0;
+    let settings = RenderSettings {
+        samples_per_pixel: 16,
+        pixel_sampling: PixelSampling::Grid,
+        ..RenderSettings::default()
+    };
+    let mut camera = Camera::new(11, 11, PI / 2.0);
+    camera.transform = Isometry3::translation(0.0, 0.0, -5.0);
+    let edge = (camera.half_width - 1.0 / 24.0_f32.sqrt()) / camera.pixel_size;
+    let sharp = render(&camera, &w);
+    let smooth = render_with(&camera, &w, &settings);
+    let x = edge.floor() as u32;
+    let (sharp, smooth) = (sharp.get_pixel(x, 5).0[0], smooth.get_pixel(x, 5).0[0]);
+    assert!(smooth > 0 && smooth != sharp);
+}
+
+#[test]
+fn test_thin_lens() {
+    let mut rng = rand::thread_rng();
+    let mut c = Camera::new(21, 21, PI / 2.0);
+    c.transform = Isometry3::translation(0.0, 0.0, -5.0);
+    assert_eq!(
+        c.sample_ray(3.5, 7.5, &mut rng).dir,
+        c.ray_for_pixel(3, 7).dir
+    );
+
+    // Rays through one image point leave different points of the lens and meet on the
+    // focal plane.
+    c.aperture = 0.2;
+    c.focal_distance = 4.0;
+    let pinhole = c.ray_for_pixel(3, 7);
+    let focus = pinhole.point_at(4.0 / -pinhole.dir.z);
+    for _ in 0..20 {
+        let r = c.sample_ray(3.5, 7.5, &mut rng);
+        assert!((r.origin - pinhole.origin).magnitude() <= 0.2 + 0.0001);
+        assert_relative_eq!(r.point_at(4.0 / -r.dir.z), focus, epsilon = 0.001);
+    }
+
+    // A four bladed aperture is a diamond.
+    c.aperture_blades = 4;
+    for _ in 0..100 {
+        let (x, y) = c.lens_point(&mut rng);
+        assert!(x.abs() + y.abs() <= 0.2 + 0.0001);
+    }
+}
+
+#[test]
+fn test_orthographic() {
+    let mut c = Camera::new(20, 10, PI / 2.0);
+    c.projection = Projection::Orthographic { width: 8.0 };
+    c.transform = Isometry3::translation(0.0, 0.0, -5.0);
+    let corner = c.ray_through(0.0, 0.0);
+    let center = c.ray_through(10.0, 5.0);
+    assert_relative_eq!(corner.origin, Point3::new(4.0, 2.0, 5.0), epsilon = 0.0001);
+    assert_relative_eq!(center.origin, Point3::new(0.0, 0.0, 5.0), epsilon = 0.0001);
+    assert_relative_eq!(corner.dir, center.dir);
+    assert_relative_eq!(center.dir, Vector3::new(0.0, 0.0, -1.0));
+}
+
+#[test]
+fn test_panoramic_projections() {
+    let mut c = Camera::new(40, 20, PI / 2.0);
+    c.projection = Projection::Equirectangular;
+    assert_relative_eq!(
+        c.ray_through(20.0, 10.0).dir,
+        -Vector3::z(),
+        epsilon = 0.0001
+    );
+    assert_relative_eq!(
+        c.ray_through(10.0, 10.0).dir,
+        Vector3::x(),
+        epsilon = 0.0001
+    );
+    assert_relative_eq!(c.ray_through(0.0, 10.0).dir, Vector3::z(), epsilon = 0.0001);
+    assert_relative_eq!(c.ray_through(7.0, 0.0).dir, Vector3::y(), epsilon = 0.0001);
+
+    let mut c = Camera::new(20, 20, PI / 2.0);
+    for mapping in [FisheyeMapping::Equidistant, FisheyeMapping::Equisolid] {
+        c.projection = Projection::Fisheye { fov: PI, mapping };
+        assert_relative_eq!(
+            c.ray_through(10.0, 10.0).dir,
+            -Vector3::z(),
+            epsilon = 0.0001
+        );
+        assert_relative_eq!(c.ray_through(10.0, 0.0).dir, Vector3::y(), epsilon = 0.0001);
+        assert_relative_eq!(c.ray_through(0.0, 10.0).dir, Vector3::x(), epsilon = 0.0001);
+    }
+    assert!(c.sees(10.0, 1.0));
+    assert!(!c.sees(1.0, 1.0));
+    // Halfway out, equidistant is at 45 degrees and equisolid about 41.
+    c.projection = Projection::Fisheye {
+        fov: PI,
+        mapping: FisheyeMapping::Equidistant,
+    };
+    assert_relative_eq!(
+        c.ray_through(10.0, 5.0).dir.y,
+        0.5_f32.sqrt(),
+        epsilon = 0.0001
+    );
+    c.projection = Projection::Fisheye {
+        fov: PI,
+        mapping: FisheyeMapping::Equisolid,
+    };
+    let theta = 2.0 * (0.5 * (PI / 4.0).sin()).asin();
+    assert_relative_eq!(
+        c.ray_through(10.0, 5.0).dir.y,
+        theta.sin(),
+        epsilon = 0.0001
+    );
+
+    let mut c = Camera::new(60, 10, PI / 2.0);
+    c.projection = Projection::Cubemap;
+    let faces = [
+        Vector3::x(),
+        -Vector3::x(),
+        Vector3::y(),
+        -Vector3::y(),
+        Vector3::z(),
+        -Vector3::z(),
+    ];
+    for (i, face) in faces.iter().enumerate() {
+        let dir = c.ray_through(i as f32 * 10.0 + 5.0, 5.0).dir;
+        assert_relative_eq!(dir, *face, epsilon = 0.0001);
+    }
+
+    // Too tall a frame keeps the faces square, in a strip across its middle.
+    let mut c = Camera::new(60, 20, PI / 2.0);
+    c.projection = Projection::Cubemap;
+    assert!(!c.sees(5.0, 2.0) && c.sees(5.0, 10.0));
+    for (i, face) in faces.iter().enumerate() {
+        let dir = c.ray_through(i as f32 * 10.0 + 5.0, 10.0).dir;
+        assert_relative_eq!(dir, *face, epsilon = 0.0001);
+    }
+    let corner = c.ray_through(0.0, 5.0).dir;
+    assert_relative_eq!(
+        corner,
+        Vector3::new(1.0, 1.0, 1.0).normalize(),
+        epsilon = 0.0001
+    );
+}
+
+// The default world with its sphere up and to the right, seen by a 16x9 camera, and
+// settings for a soft filtered render of it.
+#[cfg(test)]
+pub(crate) fn off_center_sphere() -> (World, Camera, RenderSettings) {
+    let mut w = World::default();
+    w.objects[0].center = Point3::new(1.0, 0.5, 0.0);
+    let mut c = Camera::new(16, 9, PI / 2.0);
+    c.transform = Isometry3::translation(0.0, 0.0, -5.0);
+    let settings = RenderSettings {
+        filter: Filter::Tent { radius: 2.0 },
+        samples_per_pixel: 4,
+        pixel_sampling: PixelSampling::Grid,
+        ..RenderSettings::default()
+    };
+    (w, c, settings)
+}
+
+// The default world lit by a gradient sky, so that only the sphere is noisy, seen by a
+// 16x12 camera, and settings for path tracing it.
+#[cfg(test)]
+pub(crate) fn sky_lit_sphere() -> (World, Camera, RenderSettings) {
+    let w = World {
+        background: Background::Gradient {
+            top: RayRgb::white(),
+            bottom: RayRgb::black(),
+        },
+        ambient_background: true,
+        ..World::default()
+    };
+    let mut c = Camera::new(16, 12, PI / 2.0);
+    c.transform = Isometry3::translation(0.0, 0.0, -4.0);
+    let settings = RenderSettings {
+        integrator: Integrator::Path {
+            max_depth: 3,
+            heuristic: crate::integrator::MisHeuristic::Power,
+        },
+        ..RenderSettings::default()
+    };
+    (w, c, settings)
+}
+
+#[test]
+fn test_render_region() {
+    let (w, c, _) = off_center_sphere();
+    let full = render(&c, &w);
+    assert_eq!(full.dimensions(), (16, 9));
+    // The sphere sits up and to the image's left of center.
+    assert!(full.get_pixel(6, 3).0[0] > 0);
+    assert_eq!(full.get_pixel(14, 7).0, [0, 0, 0]);
+
+    let region = render_region(&c, &w, &RenderSettings::default(), &Region::new(4, 2, 5, 3));
+    assert_eq!(region.dimensions(), (5, 3));
+    for (x, y, pixel) in region.enumerate_pixels() {
+        assert_eq!(pixel, full.get_pixel(x + 4, y + 2));
+    }
+    let clipped = render_region(
+        &c,
+        &w,
+        &RenderSettings::default(),
+        &Region::new(12, 6, 10, 10),
+    );
+    assert_eq!(clipped.dimensions(), (4, 3));
+}
+
+#[test]
+fn test_parallel_render() {
+    let (w, c, path) = sky_lit_sphere();
+    let settings = RenderSettings {
+        samples_per_pixel: 4,
+        pixel_sampling: PixelSampling::Jittered,
+        threads: Some(1),
+        ..path
+    };
+    let single = render_with(&c, &w, &settings);
+    for threads in [Some(3), None] {
+        let parallel = render_with(
+            &c,
+            &w,
+            &RenderSettings {
+                threads,
+                ..settings
+            },
+        );
+        assert_eq!(single, parallel);
+    }
+    for sampler in [SamplerKind::Sobol, SamplerKind::BlueNoise] {
+        let sampled = RenderSettings {
+            sampler,
+            ..settings
+        };
+        let parallel = render_with(
+            &c,
+            &w,
+            &RenderSettings {
+                threads: None,
+                ..sampled
+            },
+        );
+        assert_eq!(render_with(&c, &w, &sampled), parallel);
+        assert_ne!(single, parallel);
+    }
+    let reseeded = render_with(
+        &c,
+        &w,
+        &RenderSettings {
+            seed: 7,
+            ..settings
+        },
+    );
+    assert_ne!(single, reseeded);
+}
+
+#[test]
+fn test_filtered_render() {
+    let (w, c, settings) = off_center_sphere();
+    let sharp = render(&c, &w);
+    let lit = |image: &ImageBuffer<Rgb<u8>, Vec<u8>>| image.pixels().filter(|p| p.0[0] > 0).count();
+
+    // A wide filter spreads the sphere's samples into the black pixels around it.
+    let soft = render_with(&c, &w, &settings);
+    assert!(lit(&soft) > lit(&sharp));
+    assert_eq!(soft.get_pixel(15, 8).0, [0, 0, 0]);
+
+    // Regions still match the full render, samples from beyond their edges included.
+    let region = render_region(&c, &w, &settings, &Region::new(3, 1, 6, 4));
+    for (x, y, pixel) in region.enumerate_pixels() {
+        assert_eq!(pixel, soft.get_pixel(x + 3, y + 1));
+    }
+}
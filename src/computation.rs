@@ -1,21 +1,26 @@
 use rapier3d::na::{Point3, Vector3};
 
-use crate::sphere::Sphere;
+use crate::shape::ShapeT;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Computation<'a> {
     pub t: f32,
-    pub object: &'a Sphere,
+    pub object: &'a dyn ShapeT,
     pub point: Point3<f32>,
     pub eyev: Vector3<f32>,
     pub normalv: Vector3<f32>,
+    pub shading_normalv: Vector3<f32>,
     pub inside: bool,
     pub over_point: Point3<f32>,
+    pub under_point: Point3<f32>,
+    pub reflectv: Vector3<f32>,
+    pub n1: f32,
+    pub n2: f32,
 }
 impl<'a> Computation<'a> {
     pub fn new(
         t: f32,
-        object: &'a Sphere,
+        object: &'a dyn ShapeT,
         point: Point3<f32>,
         eyev: Vector3<f32>,
         normalv: Vector3<f32>,
@@ -23,12 +28,17 @@ impl<'a> Computation<'a> {
     ) -> Self {
         Self {
             t,
-            object: &object,
+            object,
             point,
             eyev,
             normalv,
+            shading_normalv: normalv,
             inside: false,
             over_point,
+            under_point: over_point,
+            reflectv: Vector3::zeros(),
+            n1: 1.0,
+            n2: 1.0,
         }
     }
 }
@@ -0,0 +1,196 @@
+use std::{f32::consts::PI, path::Path};
+
+#[cfg(test)]
+use approx::assert_relative_eq;
+use image::{DynamicImage, ImageResult};
+use rand::Rng;
+use rapier3d::na::Vector3;
+
+use crate::{
+    bsdf::luminance,
+    ray_rgb::RayRgb,
+    texture::{ImageTexture, WrapMode},
+};
+
+// An equirectangular image of the light arriving from every direction. The center of
+// the image looks down -Z and the top row is straight up; `rotation` turns the map
+// about Y and `strength` scales its radiance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvironmentMap {
+    image: ImageTexture,
+    pub strength: f32,
+    pub rotation: f32,
+    // Unnormalized luminance of each pixel, weighted by the solid angle it covers.
+    weights: Vec<f32>,
+    // Cumulative weights of the rows, and of the pixels within each row.
+    row_cdf: Vec<f32>,
+    pixel_cdf: Vec<f32>,
+}
+
+impl EnvironmentMap {
+    // Loads an image, such as a Radiance `.hdr` file.
+    pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<Self> {
+        Ok(Self::from_image(&image::open(path)?))
+    }
+
+    pub fn from_image(image: &DynamicImage) -> Self {
+        let image = ImageTexture::from_image(image);
+        let (width, height) = (image.width(), image.height());
+
+        let mut weights = Vec::with_capacity((width * height) as usize);
+        let mut pixel_cdf = Vec::with_capacity(weights.capacity());
+        let mut row_cdf = Vec::with_capacity(height as usize);
+        let mut total = 0.0;
+        for y in 0..height {
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            let mut row = 0.0;
+            for x in 0..width {
+                let w = luminance(&image.pixel(x, y)).max(0.0) * sin_theta;
+                weights.push(w);
+                row += w;
+                pixel_cdf.push(row);
+            }
+            total += row;
+            row_cdf.push(total);
+        }
+
+        Self {
+            image,
+            strength: 1.0,
+            rotation: 0.0,
+            weights,
+            row_cdf,
+            pixel_cdf,
+        }
+    }
+
+    pub fn radiance(&self, dir: &Vector3<f32>) -> RayRgb {
+        let (u, v) = self.uv(dir);
+        // Only the azimuth wraps around; the poles are the image's top and bottom rows.
+        self.image
+            .sample_wrapping(u, 1.0 - v, WrapMode::Repeat, WrapMode::Clamp)
+            * self.strength
+    }
+
+    // Picks a direction with probability proportional to the radiance arriving from it,
+    // returning it with its radiance and solid-angle density.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Option<(Vector3<f32>, RayRgb, f32)> {
+        let total = *self.row_cdf.last()?;
+        if total <= 0.0 {
+            return None;
+        }
+        let width = self.image.width() as usize;
+        let target = rng.gen::<f32>() * total;
+        let y = self
+            .row_cdf
+            .partition_point(|&c| c <= target)
+            .min(self.row_cdf.len() - 1);
+        let row = &self.pixel_cdf[y * width..(y + 1) * width];
+        let target = rng.gen::<f32>() * row[width - 1];
+        let x = row.partition_point(|&c| c <= target).min(width - 1);
+
+        let u = (x as f32 + rng.gen::<f32>()) / width as f32;
+        let v = (y as f32 + rng.gen::<f32>()) / self.image.height() as f32;
+        let dir = self.direction(u, v);
+        let pdf = self.pdf(&dir);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((dir, self.radiance(&dir), pdf))
+    }
+
+    // Solid-angle density with which `sample` returns `dir`.
+    pub fn pdf(&self, dir: &Vector3<f32>) -> f32 {
+        let total = match self.row_cdf.last() {
+            Some(&total) if total > 0.0 => total,
+            _ => return 0.0,
+        };
+        let (width, height) = (self.image.width(), self.image.height());
+        let (u, v) = self.uv(dir);
+        let x = ((u * width as f32) as u32).min(width - 1);
+        let y = ((v * height as f32) as u32).min(height - 1);
+        let sin_theta = (PI * v).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let p = self.weights[(y * width + x) as usize] / total;
+        p * (width * height) as f32 / (2.0 * PI * PI * sin_theta)
+    }
+
+    // (u, v) in the image for `dir`, with v = 0 at the top row.
+    fn uv(&self, dir: &Vector3<f32>) -> (f32, f32) {
+        let phi = dir.x.atan2(-dir.z) - self.rotation;
+        let theta = dir.y.clamp(-1.0, 1.0).acos();
+        ((phi / (2.0 * PI) + 0.5).rem_euclid(1.0), theta / PI)
+    }
+
+    fn direction(&self, u: f32, v: f32) -> Vector3<f32> {
+        let phi = (u - 0.5) * 2.0 * PI + self.rotation;
+        let theta = v * PI;
+        Vector3::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        )
+    }
+}
+
+#[cfg(test)]
+fn sun_map() -> EnvironmentMap {
+    let mut image = image::Rgb32FImage::from_pixel(8, 4, image::Rgb([0.1, 0.1, 0.1]));
+    image.put_pixel(4, 1, image::Rgb([50.0, 40.0, 30.0]));
+    EnvironmentMap::from_image(&DynamicImage::ImageRgb32F(image))
+}
+
+#[test]
+fn test_environment_directions() {
+    let env = sun_map();
+    let (u, v) = env.uv(&Vector3::new(0.0, 0.0, -1.0));
+    assert_relative_eq!(u, 0.5, epsilon = 0.0001);
+    assert_relative_eq!(v, 0.5, epsilon = 0.0001);
+    let dir = env.direction(0.3, 0.2);
+    let (u, v) = env.uv(&dir);
+    assert_relative_eq!(u, 0.3, epsilon = 0.0001);
+    assert_relative_eq!(v, 0.2, epsilon = 0.0001);
+    assert_relative_eq!(
+        env.radiance(&Vector3::new(0.0, -1.0, 0.0)).r,
+        0.1,
+        epsilon = 0.0001
+    );
+
+    // Looking straight up or down sees only the nearest row, not the opposite pole.
+    let mut image = image::Rgb32FImage::new(4, 2);
+    for x in 0..4 {
+        image.put_pixel(x, 0, image::Rgb([1.0, 1.0, 1.0]));
+    }
+    let sky = EnvironmentMap::from_image(&DynamicImage::ImageRgb32F(image));
+    assert_relative_eq!(sky.radiance(&Vector3::y()).r, 1.0, epsilon = 0.0001);
+    assert_relative_eq!(sky.radiance(&-Vector3::y()).r, 0.0, epsilon = 0.0001);
+}
+
+#[test]
+fn test_environment_importance_sampling() {
+    let env = sun_map();
+    let mut rng = rand::thread_rng();
+    let mut bright = 0;
+    for _ in 0..1000 {
+        let (dir, radiance, pdf) = env.sample(&mut rng).unwrap();
+        assert_relative_eq!(pdf, env.pdf(&dir), max_relative = 0.001);
+        if radiance.r > 1.0 {
+            bright += 1;
+        }
+    }
+    assert!(bright > 900);
+
+    // The density integrates to one over the sphere.
+    let n = 200;
+    let mut integral = 0.0;
+    for i in 0..n {
+        for j in 0..n {
+            let (u, v) = ((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+            let d_omega = 2.0 * PI * PI * (PI * v).sin() / (n * n) as f32;
+            integral += env.pdf(&env.direction(u, v)) * d_omega;
+        }
+    }
+    assert_relative_eq!(integral, 1.0, epsilon = 0.01);
+}
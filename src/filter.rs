@@ -0,0 +1,129 @@
+use std::f32::consts::PI;
+
+#[cfg(test)]
+use approx::assert_relative_eq;
+
+// How samples are weighted into the pixels around them, by their offset from each
+// pixel's center. `radius` is in pixels; samples further away along either axis get no
+// weight. Wider filters trade sharpness for less aliasing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    // Equal weight within the radius.
+    Box { radius: f32 },
+    // Falls linearly to zero at the radius.
+    Tent { radius: f32 },
+    // A Gaussian falling off with `alpha`, shifted down to reach zero at the radius.
+    Gaussian { radius: f32, alpha: f32 },
+    // Mitchell and Netravali's cubic, usually with b = c = 1/3 and a radius of 2. Its
+    // negative lobes sharpen edges, at the cost of slight ringing.
+    Mitchell { radius: f32, b: f32, c: f32 },
+    // A sinc windowed by a wider sinc, usually with a radius of 2 or 3. The sharpest of
+    // these filters, and the most prone to ringing.
+    Lanczos { radius: f32 },
+}
+
+// A box half a pixel wide keeps every sample in its own pixel, so renders with the
+// default filter can average each pixel's samples without splatting them.
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+impl Filter {
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius } => radius,
+        }
+    }
+
+    // Weight of a sample offset by (dx, dy) pixels from a pixel's center.
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(&self, d: f32) -> f32 {
+        match *self {
+            // Half open, so that a sample on the edge between two pixels counts once.
+            Filter::Box { radius } => {
+                if -radius <= d && d < radius {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Tent { radius } => (1.0 - d.abs() / radius).max(0.0),
+            Filter::Gaussian { radius, alpha } => {
+                ((-alpha * d * d).exp() - (-alpha * radius * radius).exp()).max(0.0)
+            }
+            Filter::Mitchell { radius, b, c } => {
+                let x = 2.0 * d.abs() / radius;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                } else if x < 2.0 {
+                    ((-b - 6.0 * c) * x.powi(3)
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Lanczos { radius } => {
+                if d.abs() < radius {
+                    sinc(d) * sinc(d / radius)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[test]
+fn test_filters() {
+    let pixel = Filter::default();
+    assert_eq!(pixel.weight(-0.5, 0.0), 1.0);
+    assert_eq!(pixel.weight(0.5, 0.0), 0.0);
+
+    let tent = Filter::Tent { radius: 2.0 };
+    assert_relative_eq!(tent.weight(1.0, 0.0), 0.5);
+    assert_relative_eq!(tent.weight(1.0, 1.0), 0.25);
+
+    let gaussian = Filter::Gaussian {
+        radius: 1.5,
+        alpha: 2.0,
+    };
+    assert!(gaussian.weight(0.0, 0.0) > gaussian.weight(0.7, 0.0));
+    assert_eq!(gaussian.weight(1.5, 0.0), 0.0);
+
+    let mitchell = Filter::Mitchell {
+        radius: 2.0,
+        b: 1.0 / 3.0,
+        c: 1.0 / 3.0,
+    };
+    assert_relative_eq!(mitchell.weight(0.0, 0.0), (8.0f32 / 9.0).powi(2));
+    assert!(mitchell.weight(1.5, 0.0) < 0.0);
+    assert_relative_eq!(mitchell.weight(2.0, 0.0), 0.0);
+
+    let lanczos = Filter::Lanczos { radius: 3.0 };
+    assert_relative_eq!(lanczos.weight(0.0, 0.0), 1.0);
+    assert_relative_eq!(lanczos.weight(1.0, 0.0), 0.0, epsilon = 1e-6);
+    assert!(lanczos.weight(1.5, 0.0) < 0.0);
+}
@@ -0,0 +1,114 @@
+use crate::shape::ShapeT;
+#[cfg(test)]
+use crate::{
+    light::pattern_at_object, pattern::Pattern, plane::Plane, ray_rgb::RayRgb, shape::ShapeBase,
+    sphere::Sphere,
+};
+#[cfg(test)]
+use approx::assert_relative_eq;
+use rapier3d::na::{Isometry3, Scale3};
+#[cfg(test)]
+use rapier3d::na::{Point3, Vector3};
+
+// Shapes placed together by one transform: `scale` followed by `transform`. Groups
+// only exist while building a scene; `into_shapes` flattens them into shapes that each
+// remember the groups they were nested in.
+#[derive(Debug)]
+pub struct Group {
+    pub transform: Isometry3<f32>,
+    pub scale: Scale3<f32>,
+    pub children: Vec<Box<dyn ShapeT>>,
+    pub groups: Vec<Group>,
+}
+
+impl Default for Group {
+    fn default() -> Self {
+        Self {
+            transform: Isometry3::identity(),
+            scale: Scale3::identity(),
+            children: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl Group {
+    // The group's shapes and those of the groups inside it, nested under their
+    // transforms.
+    pub fn into_shapes(self) -> Vec<Box<dyn ShapeT>> {
+        let placement = (self.transform, self.scale);
+        let mut shapes = self.children;
+        for group in self.groups {
+            shapes.extend(group.into_shapes());
+        }
+        for shape in &mut shapes {
+            shape.nest(&placement);
+        }
+        shapes
+    }
+}
+
+#[test]
+fn test_nested_pattern() {
+    let sphere = Sphere {
+        scale: Scale3::new(2.0, 2.0, 2.0),
+        ..Sphere::default()
+    };
+    let inner = Group {
+        transform: Isometry3::translation(5.0, 0.0, 0.0),
+        children: vec![Box::new(sphere)],
+        ..Group::default()
+    };
+    let outer = Group {
+        transform: Isometry3::rotation(Vector3::y() * std::f32::consts::FRAC_PI_2),
+        groups: vec![inner],
+        ..Group::default()
+    };
+    let shapes = outer.into_shapes();
+    assert_relative_eq!(
+        shapes[0].world_to_object(&Point3::new(1.0, 0.0, -7.0)),
+        Point3::new(1.0, 0.0, 0.5),
+        epsilon = 0.0001
+    );
+
+    let checker = Pattern::checker(RayRgb::white(), RayRgb::black());
+    assert_eq!(
+        pattern_at_object(&checker, shapes[0].as_ref(), Point3::new(0.5, 0.5, -6.0)),
+        RayRgb::white()
+    );
+    assert_eq!(
+        pattern_at_object(&checker, shapes[0].as_ref(), Point3::new(0.5, 0.5, -8.0)),
+        RayRgb::black()
+    );
+}
+
+#[test]
+fn test_nested_normal_to_object() {
+    // A tilted plane in a group stretched along X: its world normal leans further
+    // from X than the plane's own does, and has to come back straight up.
+    let plane = Plane {
+        base: ShapeBase {
+            transform: Isometry3::rotation(Vector3::z() * std::f32::consts::FRAC_PI_4),
+            ..ShapeBase::default()
+        },
+        center: Point3::origin(),
+    };
+    let group = Group {
+        scale: Scale3::new(4.0, 1.0, 1.0),
+        children: vec![Box::new(plane)],
+        ..Group::default()
+    };
+    let shapes = group.into_shapes();
+    let plane = shapes[0].as_ref();
+    let normal = plane.normal_at(&Point3::origin());
+    assert_relative_eq!(
+        normal,
+        Vector3::new(-1.0, 4.0, 0.0).normalize(),
+        epsilon = 0.0001
+    );
+    assert_relative_eq!(
+        plane.normal_to_object(&normal).normalize(),
+        Vector3::y(),
+        epsilon = 0.0001
+    );
+}
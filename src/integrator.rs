@@ -0,0 +1,261 @@
+#[cfg(test)]
+use crate::sphere::Sphere;
+#[cfg(test)]
+use approx::assert_relative_eq;
+use rand::Rng;
+use rapier3d::{
+    na::{Point3, Vector3},
+    prelude::*,
+};
+
+use crate::{
+    bdpt::trace_bidirectional,
+    bsdf::Bsdf,
+    camera::{Camera, Splat},
+    intersections::*,
+    light::{emitter_pdf, environment_pdf, sample_light, surface_color},
+    ray_rgb::RayRgb,
+    world::{color_at_remaining, hit_world, is_occluded, World, MAX_REFLECTION_DEPTH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Integrator {
+    // Phong shading with hard shadows, as in `world::color_at`.
+    #[default]
+    Whitted,
+    // Unidirectional path tracing with next-event estimation at every bounce.
+    Path {
+        max_depth: usize,
+        heuristic: MisHeuristic,
+    },
+    // Connects subpaths traced from the camera and from emissive objects. Rendering
+    // through a pinhole camera, light subpaths are also joined straight to the camera
+    // and splatted onto the image.
+    Bidirectional {
+        max_depth: usize,
+        heuristic: MisHeuristic,
+    },
+}
+
+// How light sampling and BSDF sampling are weighted against each other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MisHeuristic {
+    Balance,
+    Power,
+}
+
+impl MisHeuristic {
+    pub fn weight(&self, pdf: f32, other_pdf: f32) -> f32 {
+        let (a, b) = (self.remap(pdf), self.remap(other_pdf));
+        if a + b <= 0.0 {
+            return 0.0;
+        }
+        a / (a + b)
+    }
+
+    pub fn remap(&self, pdf: f32) -> f32 {
+        match self {
+            MisHeuristic::Balance => pdf,
+            MisHeuristic::Power => pdf * pdf,
+        }
+    }
+}
+
+impl Integrator {
+    pub fn color_at<R: Rng>(&self, world: &World, ray: &Ray, rng: &mut R) -> RayRgb {
+        match *self {
+            Integrator::Whitted => color_at_remaining(world, ray, MAX_REFLECTION_DEPTH, rng),
+            Integrator::Path {
+                max_depth,
+                heuristic,
+            } => trace_path(world, ray, max_depth, heuristic, rng),
+            Integrator::Bidirectional {
+                max_depth,
+                heuristic,
+            } => trace_bidirectional(world, ray, max_depth, heuristic, None, &mut Vec::new(), rng),
+        }
+    }
+
+    // Like `color_at`, for a ray from `camera`. Light the integrator traces straight to
+    // the camera is added to `splats`.
+    pub fn sample<R: Rng>(
+        &self,
+        camera: &Camera,
+        world: &World,
+        ray: &Ray,
+        rng: &mut R,
+        splats: &mut Vec<Splat>,
+    ) -> RayRgb {
+        match *self {
+            Integrator::Bidirectional {
+                max_depth,
+                heuristic,
+            } => {
+                let camera = Some(camera).filter(|camera| camera.is_pinhole());
+                trace_bidirectional(world, ray, max_depth, heuristic, camera, splats, rng)
+            }
+            _ => self.color_at(world, ray, rng),
+        }
+    }
+}
+
+pub fn trace_path<R: Rng>(
+    world: &World,
+    ray: &Ray,
+    max_depth: usize,
+    heuristic: MisHeuristic,
+    rng: &mut R,
+) -> RayRgb {
+    let mut radiance = RayRgb::black();
+    let mut throughput = RayRgb::white();
+    let mut ray = *ray;
+    // Density of the BSDF sample that produced `ray`, or `None` for camera rays and
+    // specular bounces, which light sampling cannot find.
+    let mut bsdf_pdf: Option<f32> = None;
+
+    for depth in 0..=max_depth {
+        let hit = match hit_world(world, &ray) {
+            Some(hit) => hit,
+            None => {
+                let dir = ray.dir.normalize();
+                if let Some(environment) = &world.environment {
+                    let weight = match bsdf_pdf {
+                        None => 1.0,
+                        Some(pdf) => heuristic.weight(pdf, environment_pdf(world, &dir)),
+                    };
+                    radiance = radiance + throughput * environment.radiance(&dir) * weight;
+                } else if bsdf_pdf.is_none() || world.ambient_background {
+                    radiance = radiance + throughput * world.background.color_at(&dir);
+                }
+                break;
+            }
+        };
+        let comps = prepare_computations(&hit, &ray);
+        let material = comps.object.material();
+
+        if material.is_emissive() && !comps.inside {
+            let weight = match bsdf_pdf {
+                None => 1.0,
+                Some(pdf) => {
+                    let light_pdf = emitter_pdf(
+                        world,
+                        comps.object,
+                        &ray.origin,
+                        &comps.point,
+                        &comps.normalv,
+                    );
+                    heuristic.weight(pdf, light_pdf)
+                }
+            };
+            radiance = radiance + throughput * material.emitted() * weight;
+        }
+        if depth == max_depth {
+            break;
+        }
+
+        let wo = comps.eyev;
+        let bsdf = Bsdf::at(
+            &comps,
+            surface_color(material, comps.object, comps.over_point),
+        );
+        radiance = radiance
+            + throughput * sample_direct(world, &bsdf, &comps.over_point, &wo, heuristic, rng);
+
+        let sample = match bsdf.sample(&wo, rng) {
+            Some(sample) => sample,
+            None => break,
+        };
+        if sample.specular {
+            throughput = throughput * sample.f * (1.0 / sample.pdf);
+            bsdf_pdf = None;
+        } else {
+            throughput = throughput * sample.f * (sample.wi.dot(&bsdf.normal) / sample.pdf);
+            bsdf_pdf = Some(sample.pdf);
+        }
+        let origin = if sample.wi.dot(&comps.normalv) < 0.0 {
+            comps.under_point
+        } else {
+            comps.over_point
+        };
+        ray = Ray::new(origin, sample.wi);
+
+        if depth >= 3 {
+            let survive = throughput
+                .r
+                .max(throughput.g)
+                .max(throughput.b)
+                .clamp(0.05, 0.95);
+            if rng.gen::<f32>() >= survive {
+                break;
+            }
+            throughput = throughput * (1.0 / survive);
+        }
+    }
+    radiance
+}
+
+// Next-event estimation: light arriving at `point` from one sampled light, weighted
+// against the chance of BSDF sampling finding the same light.
+pub fn sample_direct<R: Rng>(
+    world: &World,
+    bsdf: &Bsdf,
+    point: &Point3<f32>,
+    wo: &Vector3<f32>,
+    heuristic: MisHeuristic,
+    rng: &mut R,
+) -> RayRgb {
+    let sample = match sample_light(world, point, rng) {
+        Some(sample) => sample,
+        None => return RayRgb::black(),
+    };
+    let f = bsdf.eval(wo, &sample.wi);
+    if f == RayRgb::black() || sample.pdf <= 0.0 || is_occluded(world, point, &sample.target) {
+        return RayRgb::black();
+    }
+    let weight = if sample.is_delta {
+        1.0
+    } else {
+        heuristic.weight(sample.pdf, bsdf.pdf(wo, &sample.wi))
+    };
+    f * sample.radiance * (sample.wi.dot(&bsdf.normal) * weight / sample.pdf)
+}
+
+#[test]
+fn test_mis_heuristics() {
+    assert_relative_eq!(MisHeuristic::Balance.weight(1.0, 3.0), 0.25);
+    assert_relative_eq!(MisHeuristic::Power.weight(1.0, 3.0), 0.1);
+    assert_relative_eq!(MisHeuristic::Power.weight(0.0, 0.0), 0.0);
+}
+
+#[test]
+fn test_trace_path_sees_emitters() {
+    let mut lamp = Sphere::new(Point3::new(0.0, 3.0, 0.0), 1.0);
+    lamp.material.emission = RayRgb::new(1.0, 1.0, 1.0);
+    lamp.material.emission_strength = 5.0;
+    let floor = Sphere::new(Point3::new(0.0, -100.0, 0.0), 100.0);
+    let w = World {
+        lights: vec![],
+        objects: vec![lamp, floor],
+        emitter_samples: 1,
+        ..World::default()
+    };
+    let integrator = Integrator::Path {
+        max_depth: 4,
+        heuristic: MisHeuristic::Power,
+    };
+    let mut rng = rand::thread_rng();
+
+    let r = Ray::new(Point3::new(0.0, 3.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+    let c = integrator.color_at(&w, &r, &mut rng);
+    assert!(c.r >= 5.0);
+
+    let r = Ray::new(
+        Point3::new(0.0, 1.0, -5.0),
+        Vector3::new(0.0, -0.2, 1.0).normalize(),
+    );
+    let mut sum = RayRgb::black();
+    for _ in 0..64 {
+        sum = sum + integrator.color_at(&w, &r, &mut rng);
+    }
+    assert!(sum.r > 0.0);
+}
@@ -2,26 +2,40 @@ use std::f32::EPSILON;
 
 #[cfg(test)]
 use approx::assert_relative_eq;
+#[cfg(test)]
+use rapier3d::na::Isometry3;
 use rapier3d::{
     na::{Point3, Vector3},
     prelude::*,
 };
 
-use crate::{computation::Computation, light::lighting, ray_rgb::RayRgb, shape, sphere::Sphere};
+#[cfg(test)]
+use crate::{
+    bump::{HeightSource, NormalMap},
+    texture::{ImageTexture, Texture, UvMapping},
+};
+use crate::{
+    computation::Computation,
+    light::lighting,
+    ray_rgb::RayRgb,
+    shape::{normal_from_parent, ray_to_parent, vector_from_parent, world_to_parent, ShapeT},
+    sphere::Sphere,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Intersection<'a> {
     pub t: f32,
-    pub object: &'a Sphere,
+    pub object: &'a dyn ShapeT,
 }
 
 impl<'a> Intersection<'a> {
-    fn new(t: f32, object: &'a Sphere) -> Self {
+    fn new(t: f32, object: &'a dyn ShapeT) -> Self {
         Intersection { t, object }
     }
 }
 
 pub fn intersect<'a>(s: &'a Sphere, r: &Ray) -> Option<Vec<Intersection<'a>>> {
+    let r = &ray_to_parent(&s.parents, r);
     let sphere_to_ray = Vector3::new(
         r.origin.x - s.center.x,
         r.origin.y - s.center.y,
@@ -38,12 +52,12 @@ pub fn intersect<'a>(s: &'a Sphere, r: &Ray) -> Option<Vec<Intersection<'a>>> {
         let t1 = (-b - sqrt_d) / (2.0 * a);
         let t2 = (-b + sqrt_d) / (2.0 * a);
 
-        let xs: Vec<Intersection> = [t1, t2].iter().map(|&x| Intersection::new(x, &s)).collect();
+        let xs: Vec<Intersection> = [t1, t2].iter().map(|&x| Intersection::new(x, s)).collect();
         return Some(xs);
     }
     None
 }
-pub fn intersection<'a>(t: f32, s: &'a Sphere) -> Intersection<'a> {
+pub fn intersection<'a>(t: f32, s: &'a dyn ShapeT) -> Intersection<'a> {
     Intersection::new(t, s)
 }
 
@@ -60,12 +74,53 @@ pub fn hit<'a>(s: &'a Sphere, r: &Ray) -> Option<Intersection<'a>> {
 }
 
 pub fn normal_at(sphere: &Sphere, p: Point3<f32>) -> Vector3<f32> {
-    let object_point = sphere.transform.inverse() * p;
+    let object_point = sphere.transform.inverse() * world_to_parent(&sphere.parents, &p);
     let object_normal = object_point - sphere.center;
-    sphere
-        .transform
-        .inverse_transform_vector(&object_normal)
-        .normalize()
+    normal_from_parent(
+        &sphere.parents,
+        &sphere.transform.transform_vector(&object_normal),
+    )
+}
+
+// Direction of increasing longitude on the sphere, completing the surface frame used
+// by normal maps.
+pub fn tangent_at(sphere: &Sphere, p: Point3<f32>) -> Vector3<f32> {
+    let object_point = sphere.transform.inverse() * world_to_parent(&sphere.parents, &p);
+    vector_from_parent(
+        &sphere.parents,
+        &sphere
+            .transform
+            .transform_vector(&object_tangent(&(object_point - sphere.center))),
+    )
+}
+
+fn object_tangent(object_normal: &Vector3<f32>) -> Vector3<f32> {
+    let t = Vector3::new(object_normal.z, 0.0, -object_normal.x);
+    if t.magnitude_squared() == 0.0 {
+        return Vector3::x();
+    }
+    t.normalize()
+}
+
+// The normal used for shading: the geometric normal, perturbed by the material's
+// normal map if it has one.
+pub fn shading_normal_at(sphere: &Sphere, p: Point3<f32>) -> Vector3<f32> {
+    let normal_map = match &sphere.material.normal_map {
+        Some(normal_map) => normal_map,
+        None => return normal_at(sphere, p),
+    };
+    let object_point = sphere.transform.inverse() * world_to_parent(&sphere.parents, &p);
+    let object_normal = (object_point - sphere.center).normalize();
+    let perturbed = normal_map.perturb(
+        &object_point,
+        &sphere.center,
+        &object_normal,
+        &object_tangent(&object_normal),
+    );
+    normal_from_parent(
+        &sphere.parents,
+        &sphere.transform.transform_vector(&perturbed),
+    )
 }
 
 pub fn reflect(v_in: &Vector3<f32>, normal: &Vector3<f32>) -> Vector3<f32> {
@@ -74,7 +129,7 @@ pub fn reflect(v_in: &Vector3<f32>, normal: &Vector3<f32>) -> Vector3<f32> {
 
 pub fn prepare_computations<'a>(intersection: &'a Intersection, ray: &Ray) -> Computation<'a> {
     let point = ray.point_at(intersection.t);
-    let normalv = normal_at(&intersection.object, point);
+    let normalv = intersection.object.normal_at(&point);
     let mut comps = Computation::new(
         intersection.t,
         intersection.object,
@@ -84,16 +139,59 @@ pub fn prepare_computations<'a>(intersection: &'a Intersection, ray: &Ray) -> Co
         Point3::new(0.0, 0.0, 0.0),
     );
 
+    comps.shading_normalv = intersection.object.shading_normal_at(&point);
     if normalv.dot(&comps.eyev) < 0.0 {
         comps.inside = true;
         comps.normalv = -comps.normalv;
+        comps.shading_normalv = -comps.shading_normalv;
     } else {
         comps.inside = false;
     }
     comps.over_point = comps.point + comps.normalv * EPSILON * 100.0;
+    comps.under_point = comps.point - comps.normalv * f32::EPSILON * 100.0;
+    comps.reflectv = reflect(&ray.dir, &comps.shading_normalv);
+
+    // Objects are not nested, so a ray is either entering a sphere from the air or
+    // leaving it.
+    let refractive_index = intersection.object.material().refractive_index;
+    if comps.inside {
+        comps.n1 = refractive_index;
+        comps.n2 = 1.0;
+    } else {
+        comps.n1 = 1.0;
+        comps.n2 = refractive_index;
+    }
     comps
 }
 
+// Schlick's approximation of the fraction of light reflected at the surface.
+pub fn schlick(comps: &Computation) -> f32 {
+    let mut cos = comps.eyev.dot(&comps.normalv);
+    if comps.n1 > comps.n2 {
+        let n = comps.n1 / comps.n2;
+        let sin2_t = n * n * (1.0 - cos * cos);
+        if sin2_t > 1.0 {
+            return 1.0;
+        }
+        cos = (1.0 - sin2_t).sqrt();
+    }
+    let r0 = ((comps.n1 - comps.n2) / (comps.n1 + comps.n2)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cos).powi(5)
+}
+
+// Direction of a ray refracted through the surface, or `None` on total internal
+// reflection.
+pub fn refract(comps: &Computation, dir: &Vector3<f32>) -> Option<Vector3<f32>> {
+    let n_ratio = comps.n1 / comps.n2;
+    let cos_i = -dir.dot(&comps.normalv);
+    let sin2_t = n_ratio * n_ratio * (1.0 - cos_i * cos_i);
+    if sin2_t > 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some((comps.normalv * (n_ratio * cos_i - cos_t) + dir * n_ratio).normalize())
+}
+
 #[test]
 fn test_comp() {
     //inside
@@ -117,3 +215,78 @@ fn test_comp() {
     assert_relative_eq!(comps.eyev, Vector3::new(0.0, 0.0, -1.0));
     assert_relative_eq!(comps.normalv, Vector3::new(0.0, 0.0, -1.0));
 }
+
+#[test]
+fn test_shading_normal() {
+    let r = Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+    let mut shape = Sphere::default();
+    let i = intersection(4.0, &shape);
+    let comps = prepare_computations(&i, &r);
+    assert_eq!(comps.shading_normalv, comps.normalv);
+
+    let mut stripes = image::RgbImage::new(2, 1);
+    stripes.put_pixel(1, 0, image::Rgb([255, 255, 255]));
+    shape.material.normal_map = Some(NormalMap::Bump {
+        height: HeightSource::Image(Texture::new(
+            ImageTexture::from_image(&image::DynamicImage::ImageRgb8(stripes)),
+            UvMapping::Planar,
+        )),
+        strength: 0.5,
+    });
+    let r = Ray::new(Point3::new(0.5, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+    let i = intersection(5.0 - 0.75_f32.sqrt(), &shape);
+    let comps = prepare_computations(&i, &r);
+    assert_relative_eq!(
+        comps.normalv,
+        Vector3::new(0.5, 0.75_f32.sqrt(), 0.0),
+        epsilon = 0.0001
+    );
+    assert!(comps.shading_normalv.x < comps.normalv.x);
+    assert_relative_eq!(comps.shading_normalv.magnitude(), 1.0, epsilon = 0.0001);
+}
+
+#[test]
+fn test_rotated_surface_frame() {
+    // A quarter turn about x carries the sphere's center to (0, -1, 0).
+    let mut shape = Sphere::new(Point3::new(0.0, 0.0, 1.0), 1.0);
+    shape.transform = Isometry3::rotation(Vector3::x() * std::f32::consts::FRAC_PI_2);
+    let p = Point3::new(1.0, -1.0, 0.0);
+    assert_relative_eq!(
+        normal_at(&shape, p),
+        Vector3::new(1.0, 0.0, 0.0),
+        epsilon = 0.0001
+    );
+    assert_relative_eq!(
+        tangent_at(&shape, p),
+        Vector3::new(0.0, 1.0, 0.0),
+        epsilon = 0.0001
+    );
+}
+
+#[test]
+fn test_refraction() {
+    let mut shape = Sphere::default();
+    shape.material.transparency = 1.0;
+    shape.material.refractive_index = 1.5;
+
+    let r = Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+    let i = intersection(4.0, &shape);
+    let comps = prepare_computations(&i, &r);
+    assert_relative_eq!(comps.n1, 1.0);
+    assert_relative_eq!(comps.n2, 1.5);
+    assert!(comps.under_point.z > comps.point.z);
+    assert_relative_eq!(schlick(&comps), 0.04, epsilon = 0.0001);
+    assert_relative_eq!(
+        refract(&comps, &r.dir).unwrap(),
+        Vector3::new(0.0, 0.0, 1.0),
+        epsilon = 0.0001
+    );
+
+    let half = 2.0_f32.sqrt() / 2.0;
+    let r = Ray::new(Point3::new(0.0, 0.0, half), Vector3::new(0.0, 1.0, 0.0));
+    let i = intersection(half, &shape);
+    let comps = prepare_computations(&i, &r);
+    assert!(comps.inside);
+    assert_relative_eq!(schlick(&comps), 1.0);
+    assert_eq!(refract(&comps, &r.dir), None);
+}
@@ -1,11 +1,28 @@
+pub mod adaptive;
+pub mod background;
+pub mod bdpt;
+pub mod bsdf;
+pub mod bump;
 pub mod camera;
 pub mod computation;
+pub mod environment;
+pub mod filter;
+pub mod group;
+pub mod integrator;
 pub mod intersections;
 pub mod light;
 mod materials;
+pub mod noise;
 pub mod pattern;
+pub mod photon;
 pub mod plane;
+pub mod progressive;
 pub mod ray_rgb;
+pub mod sampler;
 pub mod shape;
+pub mod sky;
 pub mod sphere;
+pub mod stereo;
+pub mod texture;
+pub mod tiles;
 pub mod world;
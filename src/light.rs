@@ -1,10 +1,13 @@
 #[cfg(test)]
 use approx::assert_relative_eq;
 use num::pow;
+use rand::Rng;
 use rapier3d::na::{Point3, Vector3};
+use std::f32::consts::PI;
 
 use crate::{
-    intersections::*, materials::Material, pattern::Pattern, ray_rgb::RayRgb, sphere::Sphere,
+    intersections::*, materials::Material, pattern::Pattern, ray_rgb::RayRgb, shape::ShapeT,
+    sphere::Sphere, texture::Texture, world::World,
 };
 
 pub struct PointLight {
@@ -23,6 +26,116 @@ impl PointLight {
     }
 }
 
+// A light sampled from a shading point for next-event estimation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightSample {
+    pub wi: Vector3<f32>,
+    pub target: Point3<f32>,
+    pub radiance: RayRgb,
+    pub pdf: f32,
+    pub is_delta: bool,
+}
+
+// Shadow rays towards the environment stop this far from the shading point.
+pub const ENVIRONMENT_DISTANCE: f32 = 1.0e5;
+
+pub fn light_count(world: &World) -> usize {
+    world.lights.len() + emitters(world).count() + world.environment.is_some() as usize
+}
+
+// Emissive spheres among the world's objects, which are the only shapes with light
+// sampled from their surfaces.
+pub fn emitters(world: &World) -> impl Iterator<Item = &Sphere> {
+    world.objects.iter().filter(|o| o.material.is_emissive())
+}
+
+// The emitter `object` is, if it is one.
+pub fn as_emitter<'a>(world: &'a World, object: &dyn ShapeT) -> Option<&'a Sphere> {
+    emitters(world).find(|emitter| std::ptr::addr_eq(*emitter, object))
+}
+
+// Picks one light uniformly and samples it as seen from `point`. Point lights keep the
+// Phong convention of no falloff, scaled by PI so a white light matches `lighting`.
+pub fn sample_light<R: Rng>(
+    world: &World,
+    point: &Point3<f32>,
+    rng: &mut R,
+) -> Option<LightSample> {
+    let count = light_count(world);
+    if count == 0 {
+        return None;
+    }
+    let index = rng.gen_range(0..count);
+    let select_pdf = 1.0 / count as f32;
+
+    if let Some(light) = world.lights.get(index) {
+        return Some(LightSample {
+            wi: (light.position - point).normalize(),
+            target: light.position,
+            radiance: light.color * light.intensity * PI,
+            pdf: select_pdf,
+            is_delta: true,
+        });
+    }
+
+    let emitter = match emitters(world).nth(index - world.lights.len()) {
+        Some(emitter) => emitter,
+        None => {
+            let (wi, radiance, pdf) = world.environment.as_ref()?.sample(rng)?;
+            return Some(LightSample {
+                wi,
+                target: point + wi * ENVIRONMENT_DISTANCE,
+                radiance,
+                pdf: select_pdf * pdf,
+                is_delta: false,
+            });
+        }
+    };
+    let (p, n) = emitter.sample_surface(rng.gen(), rng.gen());
+    let v = p - point;
+    let wi = v.normalize();
+    let cos_light = n.dot(&-wi);
+    if cos_light <= 0.0 {
+        return None;
+    }
+    Some(LightSample {
+        wi,
+        target: p + n * f32::EPSILON * 100.0,
+        radiance: emitter.material.emitted(),
+        pdf: select_pdf * v.magnitude_squared() / (cos_light * emitter.area()),
+        is_delta: false,
+    })
+}
+
+// Solid-angle density with which `sample_light` would pick `light_point` on `object`
+// from `point`, which is zero for shapes that are not emitters.
+pub fn emitter_pdf(
+    world: &World,
+    object: &dyn ShapeT,
+    point: &Point3<f32>,
+    light_point: &Point3<f32>,
+    light_normal: &Vector3<f32>,
+) -> f32 {
+    let emitter = match as_emitter(world, object) {
+        Some(emitter) => emitter,
+        None => return 0.0,
+    };
+    let v = light_point - point;
+    let cos_light = light_normal.dot(&-v.normalize()).abs();
+    if cos_light <= 0.0 {
+        return 0.0;
+    }
+    v.magnitude_squared() / (cos_light * emitter.area() * light_count(world) as f32)
+}
+
+// Solid-angle density with which `sample_light` would pick the environment along `dir`.
+pub fn environment_pdf(world: &World, dir: &Vector3<f32>) -> f32 {
+    match &world.environment {
+        Some(environment) => environment.pdf(dir) / light_count(world) as f32,
+        None => 0.0,
+    }
+}
+
 pub fn lighting(
     material: &Material,
     light: &PointLight,
@@ -30,50 +143,80 @@ pub fn lighting(
     eyev: Vector3<f32>,
     normalv: Vector3<f32>,
     in_shadow: bool,
-    object: &Sphere,
+    object: &dyn ShapeT,
 ) -> RayRgb {
-    let effective_color;
-    if material.pattern.is_some() {
-        effective_color =
-            stripe_at_object(&material.pattern.as_ref().unwrap(), &object, point) * light.intensity;
-    } else {
-        effective_color = material.color * light.intensity;
-    }
+    let light_color = light.color * light.intensity;
+    let effective_color = surface_color(material, object, point) * light_color;
 
     let lightv = (light.position - point).normalize();
     let ambient = effective_color * material.ambient;
     if in_shadow {
         return ambient;
     }
-    let light_dot_normal = lightv.dot(&normalv);
 
-    let reflectv;
-    let reflect_dot_eye;
-    let factor;
-    let diffuse;
-    let specular;
+    ambient
+        + diffuse_specular(
+            material,
+            effective_color,
+            light_color,
+            lightv,
+            eyev,
+            normalv,
+        )
+}
+
+pub fn surface_color(material: &Material, object: &dyn ShapeT, point: Point3<f32>) -> RayRgb {
+    if let Some(texture) = &material.texture {
+        return texture_at_object(texture, object, point);
+    }
+    match &material.pattern {
+        Some(pattern) => pattern_at_object(pattern, object, point),
+        None => material.color,
+    }
+}
 
+// Diffuse and specular response to light of `light_color` arriving along `lightv`,
+// where `effective_color` is the surface color already tinted by the light.
+pub fn diffuse_specular(
+    material: &Material,
+    effective_color: RayRgb,
+    light_color: RayRgb,
+    lightv: Vector3<f32>,
+    eyev: Vector3<f32>,
+    normalv: Vector3<f32>,
+) -> RayRgb {
+    let light_dot_normal = lightv.dot(&normalv);
     if light_dot_normal < 0.0 {
-        diffuse = RayRgb::black();
-        specular = 0.0;
-    } else {
-        diffuse = effective_color * material.diffuse * light_dot_normal;
-        reflectv = reflect(&(-lightv), &normalv);
-        reflect_dot_eye = reflectv.dot(&eyev);
-        if reflect_dot_eye <= 0.0 {
-            specular = 0.0;
-        } else {
-            factor = pow(reflect_dot_eye, material.shininess);
-            specular = light.intensity as f32 * material.specular * factor;
-        }
+        return RayRgb::black();
     }
 
-    ambient + diffuse + specular
+    let diffuse = effective_color * material.diffuse * light_dot_normal;
+    let reflectv = reflect(&(-lightv), &normalv);
+    let reflect_dot_eye = reflectv.dot(&eyev);
+    if reflect_dot_eye <= 0.0 {
+        return diffuse;
+    }
+    let factor = pow(reflect_dot_eye, material.shininess);
+    diffuse + light_color * (material.specular * factor)
 }
 
-pub fn stripe_at_object(pattern: &Pattern, object: &Sphere, world_point: Point3<f32>) -> RayRgb {
-    let op = object.transform.inverse_transform_point(&world_point);
-    pattern.stripe_at(&op)
+pub fn pattern_at_object(
+    pattern: &Pattern,
+    object: &dyn ShapeT,
+    world_point: Point3<f32>,
+) -> RayRgb {
+    pattern.color_at(&object.world_to_object(&world_point))
+}
+
+// Textures are mapped around the shape's center, facing the way its surface does.
+pub fn texture_at_object(
+    texture: &Texture,
+    object: &dyn ShapeT,
+    world_point: Point3<f32>,
+) -> RayRgb {
+    let op = object.world_to_object(&world_point);
+    let normal = object.normal_to_object(&object.normal_at(&world_point));
+    texture.color_at(&Point3::from(op - object.center()), &normal.normalize())
 }
 
 // #[test]
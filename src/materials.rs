@@ -1,6 +1,6 @@
 use image::Rgb;
 
-use crate::{pattern::Pattern, ray_rgb::RayRgb};
+use crate::{bump::NormalMap, pattern::Pattern, ray_rgb::RayRgb, texture::Texture};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Material {
@@ -10,6 +10,13 @@ pub struct Material {
     pub specular: f32,
     pub shininess: usize,
     pub pattern: Option<Pattern>,
+    pub texture: Option<Texture>,
+    pub normal_map: Option<NormalMap>,
+    pub emission: RayRgb,
+    pub emission_strength: f32,
+    pub reflective: f32,
+    pub transparency: f32,
+    pub refractive_index: f32,
 }
 
 impl Material {
@@ -19,6 +26,19 @@ impl Material {
             ..Default::default()
         }
     }
+
+    pub fn emitted(&self) -> RayRgb {
+        self.emission * self.emission_strength
+    }
+
+    pub fn is_specular(&self) -> bool {
+        self.reflective > 0.0 || self.transparency > 0.0
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        let e = self.emitted();
+        e.r > 0.0 || e.g > 0.0 || e.b > 0.0
+    }
 }
 
 impl Default for Material {
@@ -30,6 +50,13 @@ impl Default for Material {
             specular: 0.9,
             shininess: 200,
             pattern: None,
+            texture: None,
+            normal_map: None,
+            emission: RayRgb::black(),
+            emission_strength: 1.0,
+            reflective: 0.0,
+            transparency: 0.0,
+            refractive_index: 1.0,
         }
     }
 }
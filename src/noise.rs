@@ -0,0 +1,251 @@
+#[cfg(test)]
+use approx::assert_relative_eq;
+use rapier3d::na::{Point3, Vector3};
+
+// Ken Perlin's reference permutation of 0..256.
+const PERMUTATION: [u8; 256] = [
+    151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233, 7, 225, 140, 36, 103, 30, 69,
+    142, 8, 99, 37, 240, 21, 10, 23, 190, 6, 148, 247, 120, 234, 75, 0, 26, 197, 62, 94, 252, 219,
+    203, 117, 35, 11, 32, 57, 177, 33, 88, 237, 149, 56, 87, 174, 20, 125, 136, 171, 168, 68, 175,
+    74, 165, 71, 134, 139, 48, 27, 166, 77, 146, 158, 231, 83, 111, 229, 122, 60, 211, 133, 230,
+    220, 105, 92, 41, 55, 46, 245, 40, 244, 102, 143, 54, 65, 25, 63, 161, 1, 216, 80, 73, 209, 76,
+    132, 187, 208, 89, 18, 169, 200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109, 198, 173,
+    186, 3, 64, 52, 217, 226, 250, 124, 123, 5, 202, 38, 147, 118, 126, 255, 82, 85, 212, 207, 206,
+    59, 227, 47, 16, 58, 17, 182, 189, 28, 42, 223, 183, 170, 213, 119, 248, 152, 2, 44, 154, 163,
+    70, 221, 153, 101, 155, 167, 43, 172, 9, 129, 22, 39, 253, 19, 98, 108, 110, 79, 113, 224, 232,
+    178, 185, 112, 104, 218, 246, 97, 228, 251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162,
+    241, 81, 51, 145, 235, 249, 14, 239, 107, 49, 192, 214, 31, 181, 199, 106, 157, 184, 84, 204,
+    176, 115, 121, 50, 45, 127, 4, 150, 254, 138, 236, 205, 93, 222, 114, 67, 29, 24, 72, 243, 141,
+    128, 195, 78, 66, 215, 61, 156, 180,
+];
+
+fn hash(i: i32) -> usize {
+    PERMUTATION[(i & 255) as usize] as usize
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+fn grad(hash: usize, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+// Improved Perlin gradient noise, roughly in [-1, 1] and zero at integer lattice points.
+pub fn perlin(p: &Point3<f32>) -> f32 {
+    let (xf, yf, zf) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (xi, yi, zi) = (xf as i32 & 255, yf as i32 & 255, zf as i32 & 255);
+    let (x, y, z) = (p.x - xf, p.y - yf, p.z - zf);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let a = hash(xi) + yi as usize;
+    let aa = hash(a as i32) + zi as usize;
+    let ab = hash(a as i32 + 1) + zi as usize;
+    let b = hash(xi + 1) + yi as usize;
+    let ba = hash(b as i32) + zi as usize;
+    let bb = hash(b as i32 + 1) + zi as usize;
+
+    let corner = |h: usize, dx: f32, dy: f32, dz: f32| grad(hash(h as i32), x - dx, y - dy, z - dz);
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(u, corner(aa, 0.0, 0.0, 0.0), corner(ba, 1.0, 0.0, 0.0)),
+            lerp(u, corner(ab, 0.0, 1.0, 0.0), corner(bb, 1.0, 1.0, 0.0)),
+        ),
+        lerp(
+            v,
+            lerp(
+                u,
+                corner(aa + 1, 0.0, 0.0, 1.0),
+                corner(ba + 1, 1.0, 0.0, 1.0),
+            ),
+            lerp(
+                u,
+                corner(ab + 1, 0.0, 1.0, 1.0),
+                corner(bb + 1, 1.0, 1.0, 1.0),
+            ),
+        ),
+    )
+}
+
+// Skews 3D space onto the simplex grid and back.
+const SKEW: f32 = 1.0 / 3.0;
+const UNSKEW: f32 = 1.0 / 6.0;
+
+// 3D simplex noise, roughly in [-1, 1]. Cheaper than Perlin noise and free of its
+// axis-aligned artifacts.
+pub fn simplex(p: &Point3<f32>) -> f32 {
+    let s = (p.x + p.y + p.z) * SKEW;
+    let (i, j, k) = ((p.x + s).floor(), (p.y + s).floor(), (p.z + s).floor());
+    let t = (i + j + k) * UNSKEW;
+    let x0 = Vector3::new(p.x - (i - t), p.y - (j - t), p.z - (k - t));
+
+    // The two middle corners of the simplex containing the point.
+    let (o1, o2) = if x0.x >= x0.y {
+        if x0.y >= x0.z {
+            ([1, 0, 0], [1, 1, 0])
+        } else if x0.x >= x0.z {
+            ([1, 0, 0], [1, 0, 1])
+        } else {
+            ([0, 0, 1], [1, 0, 1])
+        }
+    } else if x0.y < x0.z {
+        ([0, 0, 1], [0, 1, 1])
+    } else if x0.x < x0.z {
+        ([0, 1, 0], [0, 1, 1])
+    } else {
+        ([0, 1, 0], [1, 1, 0])
+    };
+
+    let (ii, jj, kk) = (i as i32, j as i32, k as i32);
+    let corners = [[0, 0, 0], o1, o2, [1, 1, 1]];
+    let mut n = 0.0;
+    for (c, o) in corners.iter().enumerate() {
+        let d = x0 - Vector3::new(o[0] as f32, o[1] as f32, o[2] as f32)
+            + Vector3::repeat(c as f32 * UNSKEW);
+        let falloff = 0.6 - d.magnitude_squared();
+        if falloff > 0.0 {
+            let h = hash3(ii + o[0], jj + o[1], kk + o[2]);
+            n += falloff.powi(4) * grad(h, d.x, d.y, d.z);
+        }
+    }
+    32.0 * n
+}
+
+// Cellular noise: the distance from `p` to the nearest of one feature point scattered
+// in each unit cell, in [0, about 1].
+pub fn worley(p: &Point3<f32>) -> f32 {
+    let (xi, yi, zi) = (p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32);
+    let mut nearest = f32::MAX;
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            for dz in -1..=1 {
+                let (x, y, z) = (xi + dx, yi + dy, zi + dz);
+                let h = hash3(x, y, z);
+                let feature = Point3::new(
+                    x as f32 + hash(h as i32) as f32 / 255.0,
+                    y as f32 + hash(h as i32 + 1) as f32 / 255.0,
+                    z as f32 + hash(h as i32 + 2) as f32 / 255.0,
+                );
+                nearest = nearest.min((feature - p).magnitude_squared());
+            }
+        }
+    }
+    nearest.sqrt()
+}
+
+fn hash3(x: i32, y: i32, z: i32) -> usize {
+    hash(x + hash(y + hash(z) as i32) as i32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NoiseKind {
+    #[default]
+    Perlin,
+    Simplex,
+    Worley,
+}
+
+impl NoiseKind {
+    pub fn noise(&self, p: &Point3<f32>) -> f32 {
+        match self {
+            NoiseKind::Perlin => perlin(p),
+            NoiseKind::Simplex => simplex(p),
+            NoiseKind::Worley => worley(p),
+        }
+    }
+
+    // Fractal Brownian motion: `octaves` layers of noise, each at twice the frequency
+    // and half the amplitude of the last, normalized to the range of a single layer.
+    pub fn fbm(&self, p: &Point3<f32>, octaves: u32) -> f32 {
+        self.octaves(p, octaves, |n| n)
+    }
+
+    // Like `fbm` but summing the absolute value of each layer, which gives sharp creases
+    // where the noise crosses zero.
+    pub fn turbulence(&self, p: &Point3<f32>, octaves: u32) -> f32 {
+        self.octaves(p, octaves, f32::abs)
+    }
+
+    fn octaves(&self, p: &Point3<f32>, octaves: u32, layer: impl Fn(f32) -> f32) -> f32 {
+        let (mut sum, mut total) = (0.0, 0.0);
+        let (mut frequency, mut amplitude) = (1.0, 1.0);
+        for _ in 0..octaves.max(1) {
+            sum += layer(self.noise(&Point3::from(p.coords * frequency))) * amplitude;
+            total += amplitude;
+            frequency *= 2.0;
+            amplitude *= 0.5;
+        }
+        sum / total
+    }
+
+    // Three decorrelated lookups, used to jitter points.
+    pub fn vector(&self, p: &Point3<f32>) -> Vector3<f32> {
+        Vector3::new(
+            self.noise(p),
+            self.noise(&(p + Vector3::new(31.4, 17.7, 5.3))),
+            self.noise(&(p + Vector3::new(-12.9, 47.1, 23.8))),
+        )
+    }
+}
+
+#[test]
+fn test_noise() {
+    assert_relative_eq!(perlin(&Point3::new(1.0, 2.0, 3.0)), 0.0);
+    let mut min = f32::MAX;
+    let mut max = f32::MIN;
+    for i in 0..1000 {
+        let t = i as f32 * 0.137;
+        let n = perlin(&Point3::new(t, t * 0.7 - 3.0, -t * 1.3));
+        min = min.min(n);
+        max = max.max(n);
+    }
+    assert!(min > -1.1 && max < 1.1);
+    assert!(max - min > 0.5);
+
+    // Noise is continuous.
+    let p = Point3::new(0.3, 0.4, 0.5);
+    assert_relative_eq!(
+        perlin(&p),
+        perlin(&(p + Vector3::new(0.0001, 0.0, 0.0))),
+        epsilon = 0.001
+    );
+}
+
+#[test]
+fn test_simplex_worley_and_fractals() {
+    let samples: Vec<Point3<f32>> = (0..500)
+        .map(|i| {
+            let t = i as f32 * 0.173;
+            Point3::new(t, -t * 0.61 + 2.0, t * 1.37 - 5.0)
+        })
+        .collect();
+    for kind in [NoiseKind::Simplex, NoiseKind::Worley] {
+        let values: Vec<f32> = samples.iter().map(|p| kind.noise(p)).collect();
+        let min = values.iter().cloned().fold(f32::MAX, f32::min);
+        let max = values.iter().cloned().fold(f32::MIN, f32::max);
+        assert!(min >= -1.1 && max <= 1.8, "{:?} {} {}", kind, min, max);
+        assert!(max - min > 0.3);
+    }
+    assert!(samples.iter().all(|p| worley(p) >= 0.0));
+
+    let p = Point3::new(0.3, 0.4, 0.5);
+    assert_relative_eq!(NoiseKind::Perlin.fbm(&p, 1), perlin(&p));
+    assert!(samples
+        .iter()
+        .all(|p| NoiseKind::Simplex.turbulence(p, 5) >= 0.0));
+}
@@ -1,33 +1,180 @@
-use rapier3d::na::Point3;
+use std::f32::consts::PI;
 
-use crate::ray_rgb::RayRgb;
+use rapier3d::na::{Isometry3, Point3};
+
+use crate::{noise::NoiseKind, ray_rgb::RayRgb};
 #[cfg(test)]
 use approx::assert_relative_eq;
 
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PatternKind {
+    // Alternates every half unit along Y.
+    #[default]
+    Stripe,
+    // Blends from `a` to `b` along X, repeating every unit.
+    Gradient,
+    // Concentric rings about the Y axis, one unit wide.
+    Ring,
+    // Unit cubes alternating in all three dimensions.
+    Checker,
+    // Blends from `a` to `b` outwards from the Y axis, repeating every unit.
+    Radial,
+    // Mixes `a` and `b` everywhere, taking `amount` of `b`.
+    Blend {
+        amount: f32,
+    },
+    // Veins of `b` in `a`, running across X and distorted by turbulence.
+    Marble,
+    // Noisy rings of `b` in `a` about the Y axis.
+    Wood,
+    // Speckles of `b` in `a` from cellular noise.
+    Granite,
+    // Soft patches of `b` in `a` from fractal simplex noise.
+    Cloud,
+}
+
+// One of the two inputs of a pattern: a fixed color, or another pattern looked up in
+// this pattern's space.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatternSource {
+    Color(RayRgb),
+    Pattern(Box<Pattern>),
+}
+
+impl PatternSource {
+    fn color_at(&self, point: &Point3<f32>) -> RayRgb {
+        match self {
+            PatternSource::Color(color) => *color,
+            PatternSource::Pattern(pattern) => pattern.color_at(point),
+        }
+    }
+}
+
+impl From<RayRgb> for PatternSource {
+    fn from(color: RayRgb) -> Self {
+        PatternSource::Color(color)
+    }
+}
+
+impl From<Pattern> for PatternSource {
+    fn from(pattern: Pattern) -> Self {
+        PatternSource::Pattern(Box::new(pattern))
+    }
+}
+
+// A procedural pattern alternating or blending between two inputs. `transform` and
+// `scale` place the pattern in the object's space, on top of the object's own
+// transform. `perturbation` jitters the lookup point by `perturbation_noise` of that
+// size.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Pattern {
-    a: RayRgb,
-    b: RayRgb,
+    a: PatternSource,
+    b: PatternSource,
+    pub kind: PatternKind,
+    pub transform: Isometry3<f32>,
+    pub scale: f32,
+    pub perturbation: f32,
+    pub perturbation_noise: NoiseKind,
 }
 impl Default for Pattern {
     fn default() -> Self {
-        Self {
-            a: RayRgb::white(),
-            b: RayRgb::black(),
-        }
+        Self::new(RayRgb::white(), RayRgb::black())
     }
 }
 impl Pattern {
-    pub fn new(a: RayRgb, b: RayRgb) -> Pattern {
-        Pattern { a, b }
+    pub fn new(a: impl Into<PatternSource>, b: impl Into<PatternSource>) -> Pattern {
+        Pattern::with_kind(PatternKind::Stripe, a, b)
+    }
+    pub fn with_kind(
+        kind: PatternKind,
+        a: impl Into<PatternSource>,
+        b: impl Into<PatternSource>,
+    ) -> Pattern {
+        Pattern {
+            a: a.into(),
+            b: b.into(),
+            kind,
+            transform: Isometry3::identity(),
+            scale: 1.0,
+            perturbation: 0.0,
+            perturbation_noise: NoiseKind::Perlin,
+        }
+    }
+    pub fn gradient(a: impl Into<PatternSource>, b: impl Into<PatternSource>) -> Pattern {
+        Pattern::with_kind(PatternKind::Gradient, a, b)
+    }
+    pub fn ring(a: impl Into<PatternSource>, b: impl Into<PatternSource>) -> Pattern {
+        Pattern::with_kind(PatternKind::Ring, a, b)
     }
+    pub fn checker(a: impl Into<PatternSource>, b: impl Into<PatternSource>) -> Pattern {
+        Pattern::with_kind(PatternKind::Checker, a, b)
+    }
+    pub fn radial(a: impl Into<PatternSource>, b: impl Into<PatternSource>) -> Pattern {
+        Pattern::with_kind(PatternKind::Radial, a, b)
+    }
+    pub fn blend(a: impl Into<PatternSource>, b: impl Into<PatternSource>, amount: f32) -> Pattern {
+        Pattern::with_kind(PatternKind::Blend { amount }, a, b)
+    }
+
+    // Color at `point` in object space.
+    pub fn color_at(&self, point: &Point3<f32>) -> RayRgb {
+        let mut p = self.transform.inverse_transform_point(point) / self.scale;
+        if self.perturbation != 0.0 {
+            p += self.perturbation_noise.vector(&p) * self.perturbation;
+        }
+        match self.kind {
+            PatternKind::Stripe => self.stripe_at(&p),
+            PatternKind::Gradient => self.mix(&p, p.x - p.x.floor()),
+            PatternKind::Ring => {
+                let distance = (p.x * p.x + p.z * p.z).sqrt();
+                self.alternate(&p, distance.floor())
+            }
+            PatternKind::Checker => self.alternate(&p, p.x.floor() + p.y.floor() + p.z.floor()),
+            PatternKind::Radial => {
+                let distance = (p.x * p.x + p.z * p.z).sqrt();
+                self.mix(&p, distance - distance.floor())
+            }
+            PatternKind::Blend { amount } => self.mix(&p, amount),
+            PatternKind::Marble => {
+                let turbulence = NoiseKind::Perlin.turbulence(&p, 6);
+                self.mix(&p, 0.5 + 0.5 * ((p.x + 4.0 * turbulence) * PI).sin())
+            }
+            PatternKind::Wood => {
+                let grain = (p.x * p.x + p.z * p.z).sqrt() * 4.0
+                    + 0.5 * NoiseKind::Perlin.fbm(&Point3::from(p.coords * 2.0), 3);
+                self.mix(&p, grain - grain.floor())
+            }
+            PatternKind::Granite => {
+                let speckle = NoiseKind::Worley.fbm(&Point3::from(p.coords * 4.0), 3);
+                self.mix(&p, (1.0 - 2.0 * speckle).clamp(0.0, 1.0))
+            }
+            PatternKind::Cloud => {
+                let density = NoiseKind::Simplex.fbm(&p, 6);
+                self.mix(&p, (0.5 + 0.5 * density).clamp(0.0, 1.0))
+            }
+        }
+    }
+
+    // Stripes at `point` in pattern space.
     pub fn stripe_at(&self, point: &Point3<f32>) -> RayRgb {
         if ((point.y * 2.0).floor()) % 2.0 == 0.0 {
-            self.a
+            self.a.color_at(point)
         } else {
-            self.b
+            self.b.color_at(point)
         }
     }
+
+    fn alternate(&self, point: &Point3<f32>, cell: f32) -> RayRgb {
+        if cell.rem_euclid(2.0) == 0.0 {
+            self.a.color_at(point)
+        } else {
+            self.b.color_at(point)
+        }
+    }
+
+    fn mix(&self, point: &Point3<f32>, t: f32) -> RayRgb {
+        self.a.color_at(point) * (1.0 - t) + self.b.color_at(point) * t
+    }
 }
 
 #[test]
@@ -45,3 +192,83 @@ fn test_stripe_pattern() {
         epsilon = 0.0001
     );
 }
+
+#[test]
+fn test_pattern_kinds() {
+    let (white, black) = (RayRgb::white(), RayRgb::black());
+    let gradient = Pattern::gradient(white, black);
+    assert_relative_eq!(gradient.color_at(&Point3::new(0.25, 0.0, 0.0)).r, 0.75);
+    assert_relative_eq!(gradient.color_at(&Point3::new(-0.25, 0.0, 0.0)).r, 0.25);
+
+    let ring = Pattern::ring(white, black);
+    assert_eq!(ring.color_at(&Point3::new(0.0, 0.0, 0.0)), white);
+    assert_eq!(ring.color_at(&Point3::new(0.708, 0.0, 0.708)), black);
+
+    let checker = Pattern::checker(white, black);
+    assert_eq!(checker.color_at(&Point3::new(0.99, 0.0, 0.0)), white);
+    assert_eq!(checker.color_at(&Point3::new(0.0, 1.01, 0.0)), black);
+    assert_eq!(checker.color_at(&Point3::new(-0.01, 0.0, 0.0)), black);
+
+    let radial = Pattern::radial(white, black);
+    assert_relative_eq!(radial.color_at(&Point3::new(0.0, 3.0, 0.5)).r, 0.5);
+
+    // The pattern's own transform is applied before the lookup.
+    let mut moved = Pattern::checker(white, black);
+    moved.transform = Isometry3::translation(0.5, 0.0, 0.0);
+    moved.scale = 2.0;
+    assert_eq!(moved.color_at(&Point3::new(2.0, 0.0, 0.0)), white);
+    assert_eq!(moved.color_at(&Point3::new(0.0, 0.0, 0.0)), black);
+    assert_eq!(moved.color_at(&Point3::new(2.6, 0.0, 0.0)), black);
+}
+
+#[test]
+fn test_composed_patterns() {
+    let (white, black) = (RayRgb::white(), RayRgb::black());
+    let red = RayRgb::new(1.0, 0.0, 0.0);
+    let stripes = Pattern::new(red, black);
+    let checker = Pattern::checker(stripes.clone(), white);
+    assert_eq!(checker.color_at(&Point3::new(0.5, 0.2, 0.5)), red);
+    assert_eq!(checker.color_at(&Point3::new(0.5, 0.7, 0.5)), black);
+    assert_eq!(checker.color_at(&Point3::new(1.5, 0.2, 0.5)), white);
+
+    let blend = Pattern::blend(stripes, Pattern::gradient(white, black), 0.5);
+    assert_relative_eq!(blend.color_at(&Point3::new(0.25, 0.2, 0.0)).r, 0.875);
+    assert_relative_eq!(blend.color_at(&Point3::new(0.25, 0.2, 0.0)).g, 0.375);
+
+    // Perturbation moves stripe boundaries but leaves colors untouched.
+    let mut wobbly = Pattern::new(white, black);
+    wobbly.perturbation = 0.4;
+    let mut moved = 0;
+    for i in 0..100 {
+        let p = Point3::new(i as f32 * 0.173, i as f32 * 0.05, 0.3);
+        let c = wobbly.color_at(&p);
+        assert!(c == white || c == black);
+        if c != Pattern::new(white, black).color_at(&p) {
+            moved += 1;
+        }
+    }
+    assert!(moved > 0);
+}
+
+#[test]
+fn test_noise_patterns() {
+    let (white, black) = (RayRgb::white(), RayRgb::black());
+    for kind in [
+        PatternKind::Marble,
+        PatternKind::Wood,
+        PatternKind::Granite,
+        PatternKind::Cloud,
+    ] {
+        let pattern = Pattern::with_kind(kind, white, black);
+        let values: Vec<f32> = (0..200)
+            .map(|i| {
+                let t = i as f32 * 0.0713;
+                pattern.color_at(&Point3::new(t, 0.31 - t, t * 0.5)).r
+            })
+            .collect();
+        assert!(values.iter().all(|&v| (0.0..=1.0).contains(&v)));
+        let min = values.iter().cloned().fold(f32::MAX, f32::min);
+        let max = values.iter().cloned().fold(f32::MIN, f32::max);
+        assert!(max - min > 0.3, "{:?} {} {}", kind, min, max);
+    }
+}
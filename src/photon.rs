@@ -0,0 +1,356 @@
+use std::f32::consts::PI;
+
+#[cfg(test)]
+use crate::{light::PointLight, sphere::Sphere, world::color_at};
+#[cfg(test)]
+use approx::assert_relative_eq;
+use rand::Rng;
+use rapier3d::{
+    na::{Point3, Vector3},
+    prelude::*,
+};
+
+use crate::{
+    bsdf::to_world,
+    computation::Computation,
+    intersections::*,
+    light::{emitters, surface_color},
+    ray_rgb::RayRgb,
+    world::{hit_world, World},
+};
+
+// Photons are followed through at most this many specular bounces.
+const MAX_PHOTON_BOUNCES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Photon {
+    pub position: Point3<f32>,
+    pub direction: Vector3<f32>,
+    pub power: RayRgb,
+    axis: usize,
+}
+
+impl Photon {
+    pub fn new(position: Point3<f32>, direction: Vector3<f32>, power: RayRgb) -> Self {
+        Self {
+            position,
+            direction,
+            power,
+            axis: 0,
+        }
+    }
+}
+
+// Photons stored in a balanced kd-tree: every subslice holds its splitting photon in the
+// middle, with the photons below the split to its left and the rest to its right.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    pub neighbours: usize,
+    pub max_radius: f32,
+}
+
+impl PhotonMap {
+    pub fn new(photons: Vec<Photon>, neighbours: usize, max_radius: f32) -> Self {
+        let mut photons = photons;
+        build(&mut photons);
+        Self {
+            photons,
+            neighbours,
+            max_radius,
+        }
+    }
+
+    // Emits about `count` photons from the lights and keeps those that reach a diffuse
+    // surface after one or more specular bounces.
+    pub fn caustics<R: Rng>(world: &World, count: usize, rng: &mut R) -> Self {
+        let lights = world.lights.len() + emitters(world).count();
+        let mut photons = Vec::new();
+        if lights == 0 {
+            return Self::new(photons, 50, 0.1);
+        }
+        let per_light = count / lights;
+
+        let targets: Vec<_> = world
+            .objects
+            .iter()
+            .filter(|o| o.material.is_specular())
+            .collect();
+        for light in &world.lights {
+            // Point lights have no falloff in `lighting`, so photons are given the
+            // intensity that reproduces it at the nearest specular object.
+            let reference = match targets
+                .iter()
+                .map(|t| (t.center - light.position).magnitude_squared())
+                .min_by(|a, b| a.partial_cmp(b).unwrap())
+            {
+                Some(reference) => reference,
+                None => break,
+            };
+            let intensity = light.color * light.intensity * (PI * reference);
+            let cones: Vec<(Vector3<f32>, f32)> = targets
+                .iter()
+                .map(|t| {
+                    let v = t.center - light.position;
+                    let sin2 = (t.radius * t.radius / v.magnitude_squared()).min(1.0);
+                    (v.normalize(), (1.0 - sin2).sqrt())
+                })
+                .collect();
+
+            for _ in 0..per_light {
+                let (axis, cos_max) = cones[rng.gen_range(0..cones.len())];
+                let cos = 1.0 - rng.gen::<f32>() * (1.0 - cos_max);
+                let sin = (1.0 - cos * cos).max(0.0).sqrt();
+                let phi = 2.0 * PI * rng.gen::<f32>();
+                let dir = to_world(&axis, Vector3::new(sin * phi.cos(), sin * phi.sin(), cos));
+
+                let pdf: f32 = cones
+                    .iter()
+                    .filter(|(axis, cos_max)| axis.dot(&dir) >= *cos_max)
+                    .map(|(_, cos_max)| 1.0 / (2.0 * PI * (1.0 - cos_max)))
+                    .sum::<f32>()
+                    / cones.len() as f32;
+                let power = intensity * (1.0 / (pdf * per_light as f32));
+                trace_photon(
+                    world,
+                    Ray::new(light.position, dir),
+                    power,
+                    rng,
+                    &mut photons,
+                );
+            }
+        }
+
+        for emitter in emitters(world) {
+            let power = emitter.material.emitted() * (PI * emitter.area() / per_light as f32);
+            for _ in 0..per_light {
+                let (p, n) = emitter.sample_surface(rng.gen(), rng.gen());
+                let (u1, u2): (f32, f32) = (rng.gen(), rng.gen());
+                let r = u1.sqrt();
+                let phi = 2.0 * PI * u2;
+                let dir = to_world(
+                    &n,
+                    Vector3::new(r * phi.cos(), r * phi.sin(), (1.0 - u1).max(0.0).sqrt()),
+                );
+                let origin = p + n * f32::EPSILON * 100.0;
+                trace_photon(world, Ray::new(origin, dir), power, rng, &mut photons);
+            }
+        }
+
+        Self::new(photons, 50, 0.1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    // Up to `k` photons within `max_radius` of `point`, closest first, with their
+    // squared distances.
+    pub fn nearest(&self, point: &Point3<f32>, k: usize, max_radius: f32) -> Vec<(f32, &Photon)> {
+        let mut found = Vec::with_capacity(k + 1);
+        if k > 0 {
+            self.search(
+                0,
+                self.photons.len(),
+                point,
+                k,
+                max_radius * max_radius,
+                &mut found,
+            );
+        }
+        found
+            .into_iter()
+            .map(|(d, i)| (d, &self.photons[i]))
+            .collect()
+    }
+
+    fn search(
+        &self,
+        lo: usize,
+        hi: usize,
+        point: &Point3<f32>,
+        k: usize,
+        max_distance_squared: f32,
+        found: &mut Vec<(f32, usize)>,
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        let photon = &self.photons[mid];
+        let diff = point[photon.axis] - photon.position[photon.axis];
+        let (near, far) = if diff < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+
+        self.search(near.0, near.1, point, k, max_distance_squared, found);
+
+        let bound = |found: &Vec<(f32, usize)>| {
+            if found.len() == k {
+                found[k - 1].0
+            } else {
+                max_distance_squared
+            }
+        };
+        let d = (photon.position - point).magnitude_squared();
+        if d < bound(found) {
+            let index = found.partition_point(|&(other, _)| other <= d);
+            found.insert(index, (d, mid));
+            found.truncate(k);
+        }
+        if diff * diff < bound(found) {
+            self.search(far.0, far.1, point, k, max_distance_squared, found);
+        }
+    }
+
+    // Caustic radiance leaving the diffuse part of the surface towards the eye, from
+    // the photon density around the hit point.
+    pub fn radiance(&self, comps: &Computation) -> RayRgb {
+        let nearest = self.nearest(&comps.point, self.neighbours, self.max_radius);
+        let radius_squared = match nearest.last() {
+            Some(&(d, _)) => d.max(f32::EPSILON),
+            None => return RayRgb::black(),
+        };
+        let mut flux = RayRgb::black();
+        for (_, photon) in nearest {
+            if photon.direction.dot(&comps.normalv) < 0.0 {
+                flux = flux + photon.power;
+            }
+        }
+        let material = comps.object.material();
+        let albedo = surface_color(material, comps.object, comps.over_point) * material.diffuse;
+        albedo * flux * (1.0 / (PI * PI * radius_squared))
+    }
+}
+
+fn build(photons: &mut [Photon]) {
+    if photons.is_empty() {
+        return;
+    }
+    let mut min = photons[0].position;
+    let mut max = photons[0].position;
+    for p in photons.iter() {
+        min = min.inf(&p.position);
+        max = max.sup(&p.position);
+    }
+    let extent = max - min;
+    let axis = extent.imax();
+
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| {
+        a.position[axis].partial_cmp(&b.position[axis]).unwrap()
+    });
+    photons[mid].axis = axis;
+    let (left, right) = photons.split_at_mut(mid);
+    build(left);
+    build(&mut right[1..]);
+}
+
+// Follows a photon through specular surfaces, choosing reflection or refraction with the
+// same weights `shade_hit` gives them, and stores it on the first diffuse surface.
+fn trace_photon<R: Rng>(
+    world: &World,
+    ray: Ray,
+    power: RayRgb,
+    rng: &mut R,
+    photons: &mut Vec<Photon>,
+) {
+    let mut ray = ray;
+    let mut power = power;
+    for bounce in 0..MAX_PHOTON_BOUNCES {
+        let hit = match hit_world(world, &ray) {
+            Some(hit) => hit,
+            None => return,
+        };
+        let comps = prepare_computations(&hit, &ray);
+        let material = comps.object.material();
+        if !material.is_specular() {
+            if bounce > 0 {
+                photons.push(Photon::new(comps.point, ray.dir, power));
+            }
+            return;
+        }
+
+        let (mut reflect_weight, mut refract_weight) = (material.reflective, material.transparency);
+        if material.reflective > 0.0 && material.transparency > 0.0 {
+            let reflectance = schlick(&comps);
+            reflect_weight *= reflectance;
+            refract_weight *= 1.0 - reflectance;
+        }
+        let total = reflect_weight + refract_weight;
+        if total > 1.0 {
+            power = power * total;
+            reflect_weight /= total;
+            refract_weight /= total;
+        }
+
+        let u: f32 = rng.gen();
+        ray = if u < reflect_weight {
+            Ray::new(comps.over_point, comps.reflectv)
+        } else if u < reflect_weight + refract_weight {
+            match refract(&comps, &ray.dir) {
+                Some(dir) => Ray::new(comps.under_point, dir),
+                None => Ray::new(comps.over_point, comps.reflectv),
+            }
+        } else {
+            return;
+        };
+    }
+}
+
+#[test]
+fn test_nearest_photons() {
+    let photons = (0..10)
+        .map(|i| {
+            Photon::new(
+                Point3::new(i as f32, 0.0, 0.0),
+                Vector3::new(0.0, -1.0, 0.0),
+                RayRgb::white(),
+            )
+        })
+        .collect();
+    let map = PhotonMap::new(photons, 3, 10.0);
+    assert_eq!(map.len(), 10);
+
+    let nearest = map.nearest(&Point3::new(4.2, 0.0, 0.0), 3, 10.0);
+    let xs: Vec<f32> = nearest.iter().map(|(_, p)| p.position.x).collect();
+    assert_eq!(xs, vec![4.0, 5.0, 3.0]);
+    assert_relative_eq!(nearest[0].0, 0.04, epsilon = 0.0001);
+
+    assert_eq!(map.nearest(&Point3::new(4.5, 0.0, 0.0), 5, 1.0).len(), 2);
+}
+
+#[test]
+fn test_glass_focuses_caustic() {
+    let mut w = World {
+        lights: vec![PointLight::new(
+            RayRgb::white(),
+            Point3::new(0.0, 10.0, 0.0),
+        )],
+        ..World::default()
+    };
+    w.objects[0].material.transparency = 1.0;
+    w.objects[0].material.refractive_index = 1.5;
+    w.objects[0].material.diffuse = 0.0;
+    w.objects
+        .push(Sphere::new(Point3::new(0.0, -102.0, 0.0), 100.0));
+
+    let mut rng = rand::thread_rng();
+    let map = PhotonMap::caustics(&w, 20000, &mut rng);
+    assert!(!map.is_empty());
+
+    let under = Ray::new(
+        Point3::new(0.0, -1.5, -5.0),
+        Vector3::new(0.0, -0.5, 5.0).normalize(),
+    );
+    let without = color_at(&w, &under);
+    w.caustics = Some(map);
+    let with = color_at(&w, &under);
+    assert!(with.r > without.r);
+}
@@ -1,6 +1,28 @@
-use crate::shape::*;
-use rapier3d::{na::Point3, prelude::Ray};
+#[cfg(test)]
+use crate::{
+    camera::{render, Camera},
+    light::{pattern_at_object, surface_color},
+    pattern::Pattern,
+    ray_rgb::RayRgb,
+    texture::{ImageTexture, Texture, UvMapping},
+    world::World,
+};
+use crate::{
+    intersections::{intersection, Intersection},
+    materials::Material,
+    shape::*,
+};
+#[cfg(test)]
+use approx::assert_relative_eq;
+#[cfg(test)]
+use rapier3d::na::{Isometry3, Scale3};
+use rapier3d::{
+    na::{Point3, Vector3},
+    prelude::Ray,
+};
 
+// The XZ plane through the origin of its own space, facing up Y.
+#[derive(Debug)]
 pub struct Plane {
     pub base: ShapeBase,
     pub center: Point3<f32>,
@@ -11,10 +33,154 @@ impl ShapeT for Plane {
         self.base.intersect(ray)
     }
 
-    fn normal_at(&self, point: &Point3<f32>) -> rapier3d::na::Vector3<f32> {
-        self.base.normal_at(&point)
+    fn intersections<'a>(&'a self, ray: &Ray, xs: &mut Vec<Intersection<'a>>) {
+        let ray = ray_to_parent(&self.base.parents, ray);
+        let origin = world_to_object(&self.base.transform, &self.base.scale, &ray.origin);
+        let dir = self
+            .base
+            .transform
+            .inverse_transform_vector(&ray.dir)
+            .component_div(&self.base.scale.vector);
+        if dir.y.abs() < f32::EPSILON {
+            return;
+        }
+        xs.push(intersection(-origin.y / dir.y, self));
+    }
+
+    fn normal_at(&self, _point: &Point3<f32>) -> Vector3<f32> {
+        self.base.normal_to_world(&Vector3::y())
+    }
+
+    fn shading_normal_at(&self, point: &Point3<f32>) -> Vector3<f32> {
+        let normal_map = match &self.base.material.normal_map {
+            Some(normal_map) => normal_map,
+            None => return self.normal_at(point),
+        };
+        let perturbed = normal_map.perturb(
+            &self.world_to_object(point),
+            &self.center,
+            &Vector3::y(),
+            &Vector3::x(),
+        );
+        self.base.normal_to_world(&perturbed)
+    }
+
+    fn tangent_at(&self, _point: &Point3<f32>) -> Vector3<f32> {
+        self.base.vector_to_world(&Vector3::x())
+    }
+
+    fn world_to_object(&self, point: &Point3<f32>) -> Point3<f32> {
+        self.base.world_to_object(point)
+    }
+
+    fn normal_to_object(&self, normal: &Vector3<f32>) -> Vector3<f32> {
+        self.base.normal_to_object(normal)
+    }
+
+    fn center(&self) -> Point3<f32> {
+        self.center
+    }
+
+    fn material(&self) -> &Material {
+        &self.base.material
+    }
+
+    fn nest(&mut self, placement: &Placement) {
+        self.base.parents.push(*placement);
     }
 }
 
 #[test]
 fn test_transform() {}
+
+#[test]
+fn test_rotated_tangent() {
+    let plane = Plane {
+        base: ShapeBase {
+            transform: Isometry3::rotation(Vector3::z() * std::f32::consts::FRAC_PI_2),
+            ..ShapeBase::default()
+        },
+        center: Point3::origin(),
+    };
+    assert_relative_eq!(
+        plane.tangent_at(&Point3::origin()),
+        Vector3::new(0.0, 1.0, 0.0),
+        epsilon = 0.0001
+    );
+}
+
+#[test]
+fn test_pattern_on_plane() {
+    let plane = Plane {
+        base: ShapeBase {
+            scale: Scale3::new(2.0, 2.0, 2.0),
+            transform: Isometry3::translation(1.0, 0.0, 0.0),
+            ..ShapeBase::default()
+        },
+        center: Point3::origin(),
+    };
+    let checker = Pattern::checker(RayRgb::white(), RayRgb::black());
+    assert_eq!(
+        pattern_at_object(&checker, &plane, Point3::new(2.5, 0.0, 1.5)),
+        RayRgb::white()
+    );
+    assert_eq!(
+        pattern_at_object(&checker, &plane, Point3::new(3.5, 0.0, 1.5)),
+        RayRgb::black()
+    );
+}
+
+#[test]
+fn test_texture_on_plane() {
+    let mut halves = image::RgbImage::new(2, 1);
+    halves.put_pixel(1, 0, image::Rgb([255, 255, 255]));
+    let mut plane = Plane {
+        base: ShapeBase {
+            transform: Isometry3::translation(0.5, 0.0, 0.0),
+            ..ShapeBase::default()
+        },
+        center: Point3::origin(),
+    };
+    plane.base.material.texture = Some(Texture::new(
+        ImageTexture::from_image(&image::DynamicImage::ImageRgb8(halves)),
+        UvMapping::Planar,
+    ));
+    let material = &plane.base.material;
+    assert_eq!(
+        surface_color(material, &plane, Point3::new(0.75, 0.0, 0.25)),
+        RayRgb::black()
+    );
+    assert_eq!(
+        surface_color(material, &plane, Point3::new(1.25, 0.0, 0.25)),
+        RayRgb::white()
+    );
+}
+
+#[test]
+fn test_render_pattern_on_plane() {
+    // A checkered floor seen from straight above, one square per pixel.
+    let mut floor = Plane {
+        base: ShapeBase {
+            transform: Isometry3::translation(0.0, -1.0, 0.0),
+            ..ShapeBase::default()
+        },
+        center: Point3::origin(),
+    };
+    floor.base.material.pattern = Some(Pattern::checker(RayRgb::white(), RayRgb::black()));
+    let w = World {
+        objects: vec![],
+        shapes: vec![Box::new(floor)],
+        ..World::default()
+    };
+    let mut c = Camera::new(4, 4, std::f32::consts::FRAC_PI_2);
+    c.transform = Isometry3::look_at_rh(
+        &Point3::new(0.0, 1.0, 0.0),
+        &Point3::new(0.0, -1.0, 0.0),
+        &Vector3::z(),
+    );
+    let image = render(&c, &w);
+    let black = RayRgb::black().to_rgb();
+    for (x, y, pixel) in image.enumerate_pixels() {
+        assert_eq!(*pixel == black, (x + y) % 2 == 0, "pixel ({}, {})", x, y);
+    }
+}
@@ -0,0 +1,297 @@
+use std::time::{Duration, Instant};
+
+use image::{ImageBuffer, Rgb};
+
+#[cfg(test)]
+use crate::camera::{off_center_sphere, render_with, sky_lit_sphere, PixelSampling};
+use crate::{
+    bsdf::luminance,
+    camera::{
+        for_each_row, for_each_row_zip, gather, sample_pixel, splatted, Camera, PixelSample,
+        Region, RenderSettings, RowPair, Splat,
+    },
+    ray_rgb::RayRgb,
+    sampler::Sampler,
+    tiles::Cancel,
+    world::World,
+};
+
+// Running sums of every sample taken so far, one per pixel per pass. The image at any
+// point is their filtered average.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accumulator {
+    pub width: u32,
+    pub height: u32,
+    pub passes: u32,
+    pixels: Vec<PixelSums>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PixelSums {
+    // Filter-weighted sum of the samples reaching the pixel, and their total weight.
+    color: RayRgb,
+    weight: f32,
+    // Sums of the luminance and squared luminance of the pixel's own samples, for
+    // estimating noise.
+    luminance: f32,
+    luminance_squares: f32,
+    // Sum of the light splatted onto the pixel, one image's worth per pass.
+    splats: RayRgb,
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Self {
+        let empty = PixelSums {
+            color: RayRgb::black(),
+            weight: 0.0,
+            luminance: 0.0,
+            luminance_squares: 0.0,
+            splats: RayRgb::black(),
+        };
+        Self {
+            width,
+            height,
+            passes: 0,
+            pixels: vec![empty; (width * height) as usize],
+        }
+    }
+
+    pub fn average(&self, x: u32, y: u32) -> RayRgb {
+        let pixel = &self.pixels[(y * self.width + x) as usize];
+        let splats = pixel.splats * (1.0 / self.passes.max(1) as f32);
+        if pixel.weight > 0.0 {
+            pixel.color * (1.0 / pixel.weight) + splats
+        } else {
+            pixel.color + splats
+        }
+    }
+
+    pub fn image(&self) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(self.width, self.height, |x, y| self.average(x, y).to_rgb())
+    }
+
+    // Standard error of each pixel's mean luminance, relative to that mean and averaged
+    // over the image. Infinite until there are two passes to compare.
+    pub fn noise(&self) -> f32 {
+        if self.passes < 2 || self.pixels.is_empty() {
+            return f32::INFINITY;
+        }
+        let total: f32 = self
+            .pixels
+            .iter()
+            .map(|p| relative_error(p.luminance, p.luminance_squares, self.passes))
+            .sum();
+        total / self.pixels.len() as f32
+    }
+
+    // Adds one sample to every pixel, weighted into the pixels around it by
+    // `settings.filter`. `count` is how many passes are planned, so that grid and
+    // jittered pixel sampling can spread the passes over each pixel.
+    pub fn add_pass(
+        &mut self,
+        camera: &Camera,
+        world: &World,
+        settings: &RenderSettings,
+        count: u32,
+    ) {
+        let pass = self.passes;
+        let width = self.width as usize;
+        if width > 0 {
+            let mut samples = vec![[(0.0, 0.0, RayRgb::black())]; self.pixels.len()];
+            let mut splats = vec![Vec::new(); self.height as usize];
+            let sample_row = |(y, (pixels, splats)): RowPair<[PixelSample; 1], Vec<Splat>>| {
+                for (x, pixel) in pixels.iter_mut().enumerate() {
+                    let (x, y) = (x as u32, y as u32);
+                    // Pass `n` takes the same sample as the `n`th of a full render.
+                    let mut sampler = Sampler::new(settings.sampler, settings.seed, x, y);
+                    sampler.start_sample(pass, count);
+                    let (dx, dy) = settings.pixel_sampling.offset(pass, count, &mut sampler);
+                    let (px, py) = (x as f32 + dx, y as f32 + dy);
+                    *pixel = [(
+                        px,
+                        py,
+                        sample_pixel(
+                            camera,
+                            world,
+                            settings,
+                            px,
+                            py,
+                            &mut sampler,
+                            &mut splats[0],
+                        ),
+                    )];
+                }
+            };
+            for_each_row_zip(
+                settings.threads,
+                (&mut samples, width),
+                (&mut splats, 1),
+                sample_row,
+            );
+
+            let image = Region::new(0, 0, self.width, self.height);
+            let splatted = splatted(camera, &image, &splats, self.width * self.height);
+            let add_row = |(y, pixels): (usize, &mut [PixelSums])| {
+                for (x, pixel) in pixels.iter_mut().enumerate() {
+                    let (color, weight) =
+                        gather(&settings.filter, &samples, &image, x as u32, y as u32);
+                    let own = luminance(&samples[y * width + x][0].2);
+                    pixel.splats = pixel.splats + splatted[y * width + x];
+                    pixel.color = pixel.color + color;
+                    pixel.weight += weight;
+                    pixel.luminance += own;
+                    pixel.luminance_squares += own * own;
+                }
+            };
+            for_each_row(settings.threads, &mut self.pixels, width, add_row);
+        }
+        self.passes += 1;
+    }
+}
+
+// Standard error of the mean of `n` samples relative to that mean, from the sums of
+// their luminance and squared luminance. Dark pixels are judged against a small floor
+// rather than their own tiny mean.
+pub(crate) fn relative_error(sum: f32, sum_squares: f32, n: u32) -> f32 {
+    if n < 2 {
+        return f32::INFINITY;
+    }
+    let n = n as f32;
+    let mean = sum / n;
+    let variance = ((sum_squares / n - mean * mean) * n / (n - 1.0)).max(0.0);
+    (variance / n).sqrt() / (mean + 0.01)
+}
+
+// When a progressive render stops: whichever of the set limits is reached first. There
+// is no default, as a render with no limit set only stops when cancelled; start from
+// one of the constructors and set further limits on top.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StopCondition {
+    pub passes: Option<u32>,
+    pub time: Option<Duration>,
+    // Largest acceptable `Accumulator::noise`.
+    pub noise: Option<f32>,
+}
+
+// How many passes an open-ended render spreads over each pixel before grid and jittered
+// pixel sampling start over.
+const OPEN_ENDED_PASSES: u32 = 16;
+
+impl StopCondition {
+    pub fn passes(passes: u32) -> Self {
+        Self {
+            passes: Some(passes),
+            time: None,
+            noise: None,
+        }
+    }
+    pub fn time(time: Duration) -> Self {
+        Self {
+            passes: None,
+            time: Some(time),
+            noise: None,
+        }
+    }
+    pub fn noise(noise: f32) -> Self {
+        Self {
+            passes: None,
+            time: None,
+            noise: Some(noise),
+        }
+    }
+
+    // How many passes to stratify pixel samples over: the pass limit if there is one.
+    pub fn planned_passes(&self) -> u32 {
+        self.passes.unwrap_or(OPEN_ENDED_PASSES)
+    }
+
+    pub fn reached(&self, accumulator: &Accumulator, elapsed: Duration) -> bool {
+        self.passes
+            .is_some_and(|passes| accumulator.passes >= passes)
+            || self.time.is_some_and(|time| elapsed >= time)
+            || self.noise.is_some_and(|noise| accumulator.noise() <= noise)
+    }
+}
+
+// Renders one sample per pixel per pass, calling `on_pass` with the refined image after
+// each, until `stop` is reached or the render is cancelled. `settings.samples_per_pixel`
+// is ignored. Limits are checked between passes, so a time budget can be overrun by up
+// to one pass, and with no limit set the render runs until cancelled.
+pub fn render_progressive<F>(
+    camera: &Camera,
+    world: &World,
+    settings: &RenderSettings,
+    stop: &StopCondition,
+    cancel: &Cancel,
+    mut on_pass: F,
+) -> Accumulator
+where
+    F: FnMut(&Accumulator),
+{
+    let start = Instant::now();
+    let mut accumulator = Accumulator::new(camera.hsize, camera.vsize);
+    while !cancel.is_cancelled() && !stop.reached(&accumulator, start.elapsed()) {
+        accumulator.add_pass(camera, world, settings, stop.planned_passes());
+        on_pass(&accumulator);
+    }
+    accumulator
+}
+
+#[test]
+fn test_progressive() {
+    let (world, camera, settings) = sky_lit_sphere();
+
+    let mut noise = vec![];
+    let passes = StopCondition::passes(32);
+    let accumulator =
+        render_progressive(&camera, &world, &settings, &passes, &Cancel::new(), |a| {
+            noise.push(a.noise())
+        });
+    assert_eq!(accumulator.passes, 32);
+    assert_eq!(noise.len(), 32);
+    assert_eq!(noise[0], f32::INFINITY);
+    assert!(noise[31] < noise[3] * 0.6);
+
+    // A loose noise target stops well before the pass limit, an expired time budget
+    // before the first pass.
+    let target = StopCondition {
+        noise: Some(noise[15]),
+        ..StopCondition::passes(1000)
+    };
+    let accumulator =
+        render_progressive(&camera, &world, &settings, &target, &Cancel::new(), |_| {});
+    assert!(accumulator.passes < 1000);
+    assert!(accumulator.noise() <= noise[15]);
+    let timed = StopCondition::time(Duration::ZERO);
+    let accumulator =
+        render_progressive(&camera, &world, &settings, &timed, &Cancel::new(), |_| {});
+    assert_eq!(accumulator.passes, 0);
+
+    // Without a pass limit, passes still cycle over strata within the pixel.
+    let mut rng = rand::thread_rng();
+    let count = timed.planned_passes();
+    let grid: Vec<_> = (0..count)
+        .map(|pass| PixelSampling::Grid.offset(pass, count, &mut rng))
+        .collect();
+    assert!((1..grid.len()).all(|i| !grid[..i].contains(&grid[i])));
+    for pass in 0..100 {
+        for sampling in [PixelSampling::Grid, PixelSampling::Jittered] {
+            let (x, y) = sampling.offset(pass, count, &mut rng);
+            assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y));
+        }
+    }
+}
+
+#[test]
+fn test_progressive_filter() {
+    // Passes weighted through the filter add up to the same image as a filtered render
+    // taking all the samples at once.
+    let (world, camera, settings) = off_center_sphere();
+    let expected = render_with(&camera, &world, &settings);
+    let passes = StopCondition::passes(4);
+    let accumulator =
+        render_progressive(&camera, &world, &settings, &passes, &Cancel::new(), |_| {});
+    for (a, b) in accumulator.image().pixels().zip(expected.pixels()) {
+        assert!((0..3).all(|c| a.0[c].abs_diff(b.0[c]) <= 1));
+    }
+}
@@ -82,6 +82,18 @@ impl Sub for RayRgb {
     }
 }
 
+impl Mul<Self> for RayRgb {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        Self {
+            r: self.r * rhs.r,
+            g: self.g * rhs.g,
+            b: self.b * rhs.b,
+        }
+    }
+}
+
 impl Mul<usize> for RayRgb {
     type Output = Self;
 
@@ -0,0 +1,438 @@
+use std::sync::OnceLock;
+
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+
+use crate::camera::pixel_rng;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SamplerKind {
+    // Independent uniform random numbers.
+    #[default]
+    Random,
+    // Each dimension of a pixel's samples is split into one stratum per sample, with
+    // the strata shuffled independently per dimension.
+    Stratified,
+    // The Halton sequence, rotated per pixel.
+    Halton,
+    // The Sobol sequence, scrambled per pixel.
+    Sobol,
+    // A blue-noise mask spreads each sample's error across neighboring pixels, so that
+    // it looks like fine grain rather than blotches.
+    BlueNoise,
+}
+
+// The numbers drawn for one pixel. Each sample of the pixel is a point in a space with
+// as many dimensions as numbers the sample draws: the first two place it within the
+// pixel, the next the lens, lights and bounces, in the order they are asked for. Being
+// an `RngCore`, it can be handed to anything that takes an `Rng`.
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    pub kind: SamplerKind,
+    seed: u64,
+    x: u32,
+    y: u32,
+    index: u32,
+    count: u32,
+    dimension: u32,
+    // Used by `Random`, and by the other kinds past the dimensions they support.
+    rng: StdRng,
+}
+
+// The largest f32 below 1.
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+impl Sampler {
+    pub fn new(kind: SamplerKind, seed: u64, x: u32, y: u32) -> Self {
+        let mut sampler = Self {
+            kind,
+            seed,
+            x,
+            y,
+            index: 0,
+            count: 1,
+            dimension: 0,
+            rng: pixel_rng(seed, x, y),
+        };
+        sampler.start_sample(0, 1);
+        sampler
+    }
+
+    // Moves to sample `index` of the `count` planned for the pixel. Samples past `count`
+    // are spread as further sets of `count`.
+    pub fn start_sample(&mut self, index: u32, count: u32) {
+        self.index = index;
+        self.count = count.max(1);
+        self.dimension = 0;
+        self.rng = pixel_rng(self.seed.wrapping_add((index as u64) << 32), self.x, self.y);
+    }
+
+    // The next dimension of the current sample, in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let value = match self.kind {
+            SamplerKind::Random => return self.rng.gen(),
+            SamplerKind::Stratified => {
+                let set = self.index / self.count;
+                let scramble = self.scramble(dimension) ^ set.wrapping_mul(0x9e37_79b9);
+                let stratum = permute(self.index % self.count, self.count, scramble);
+                (stratum as f32 + self.rng.gen::<f32>()) / self.count as f32
+            }
+            SamplerKind::Halton => match PRIMES.get(dimension as usize) {
+                Some(&base) => {
+                    let rotation = self.scramble(dimension) as f64 / 4294967296.0;
+                    let value = radical_inverse(base, self.index) + rotation;
+                    (value - value.floor()) as f32
+                }
+                None => self.rng.gen(),
+            },
+            SamplerKind::Sobol => match sobol_directions().get(dimension as usize) {
+                Some(directions) => {
+                    let bits = sobol(directions, self.index) ^ self.scramble(dimension);
+                    (bits as f64 / 4294967296.0) as f32
+                }
+                None => self.rng.gen(),
+            },
+            SamplerKind::BlueNoise => {
+                // The mask is shifted per dimension, not per pixel, so that each
+                // dimension is blue noise across the image.
+                let shift = mix(self.seed ^ mix(dimension as u64 + 1));
+                let size = BLUE_NOISE_SIZE as u64;
+                let x = (self.x as u64 + shift % size) % size;
+                let y = (self.y as u64 + (shift >> 32) % size) % size;
+                let offset = blue_noise()[(y * size + x) as usize] as f64;
+                let value = offset + radical_inverse(2, self.index);
+                (value - value.floor()) as f32
+            }
+        };
+        value.min(ONE_MINUS_EPSILON)
+    }
+
+    // Random bits for the current pixel and `dimension`, the same for all its samples.
+    fn scramble(&self, dimension: u32) -> u32 {
+        let pixel = ((self.x as u64) << 32) | self.y as u64;
+        (mix(self.seed ^ mix(pixel ^ mix(dimension as u64 + 1))) >> 32) as u32
+    }
+}
+
+impl RngCore for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        match self.kind {
+            SamplerKind::Random => self.rng.next_u32(),
+            _ => (self.next_f32() as f64 * 4294967296.0) as u32,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self.kind {
+            SamplerKind::Random => self.rng.next_u64(),
+            _ => ((self.next_u32() as u64) << 32) | self.rng.next_u32() as u64,
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+// SplitMix64's finalizer.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// Kensler's hashed permutation: element `i` of a shuffle of 0..`length` chosen by `p`.
+fn permute(mut i: u32, length: u32, p: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < length {
+            return i.wrapping_add(p) % length;
+        }
+    }
+}
+
+// Bases of the Halton sequence's dimensions.
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+// `index` with its digits in `base` mirrored about the radix point.
+fn radical_inverse(base: u32, mut index: u32) -> f64 {
+    let inverse = 1.0 / base as f64;
+    let (mut value, mut digit) = (0.0, inverse);
+    while index > 0 {
+        value += (index % base) as f64 * digit;
+        index /= base;
+        digit *= inverse;
+    }
+    value
+}
+
+// Degree, polynomial coefficients and initial direction numbers of the Sobol sequence's
+// dimensions after the first, from Joe and Kuo.
+const SOBOL_PARAMETERS: [(usize, u32, [u32; 6]); 15] = [
+    (1, 0, [1, 0, 0, 0, 0, 0]),
+    (2, 1, [1, 3, 0, 0, 0, 0]),
+    (3, 1, [1, 3, 1, 0, 0, 0]),
+    (3, 2, [1, 1, 1, 0, 0, 0]),
+    (4, 1, [1, 1, 3, 3, 0, 0]),
+    (4, 4, [1, 3, 5, 13, 0, 0]),
+    (5, 2, [1, 1, 5, 5, 17, 0]),
+    (5, 4, [1, 1, 5, 5, 5, 0]),
+    (5, 7, [1, 1, 7, 11, 19, 0]),
+    (5, 11, [1, 1, 5, 1, 1, 0]),
+    (5, 13, [1, 1, 1, 3, 11, 0]),
+    (5, 14, [1, 3, 5, 5, 31, 0]),
+    (6, 1, [1, 3, 3, 9, 7, 49]),
+    (6, 13, [1, 1, 1, 15, 21, 21]),
+    (6, 16, [1, 3, 1, 13, 27, 49]),
+];
+
+fn sobol_directions() -> &'static [[u32; 32]] {
+    static DIRECTIONS: OnceLock<Vec<[u32; 32]>> = OnceLock::new();
+    DIRECTIONS.get_or_init(|| {
+        let mut dimensions = vec![std::array::from_fn(|k| 1 << (31 - k))];
+        for &(degree, coefficients, initial) in &SOBOL_PARAMETERS {
+            let mut v = [0u32; 32];
+            for k in 0..32 {
+                v[k] = if k < degree {
+                    initial[k] << (31 - k)
+                } else {
+                    let mut x = v[k - degree] ^ (v[k - degree] >> degree);
+                    for i in 1..degree {
+                        if (coefficients >> (degree - 1 - i)) & 1 == 1 {
+                            x ^= v[k - i];
+                        }
+                    }
+                    x
+                };
+            }
+            dimensions.push(v);
+        }
+        dimensions
+    })
+}
+
+fn sobol(directions: &[u32; 32], mut index: u32) -> u32 {
+    let mut bits = 0;
+    for direction in directions {
+        if index == 0 {
+            break;
+        }
+        if index & 1 == 1 {
+            bits ^= direction;
+        }
+        index >>= 1;
+    }
+    bits
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+// A tileable mask with every value in (0, 1) once, whose cells below any threshold are
+// spread as evenly as possible.
+fn blue_noise() -> &'static [f32] {
+    static MASK: OnceLock<Vec<f32>> = OnceLock::new();
+    MASK.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE))
+}
+
+// Ulichney's void-and-cluster method on a `size` by `size` torus.
+fn void_and_cluster(size: usize) -> Vec<f32> {
+    let n = size * size;
+    let kernel: Vec<f32> = (0..n)
+        .map(|i| {
+            let dx = (i % size).min(size - i % size) as f32;
+            let dy = (i / size).min(size - i / size) as f32;
+            (-(dx * dx + dy * dy) / (2.0 * 1.5 * 1.5)).exp()
+        })
+        .collect();
+    // Adds or removes the influence of a point at `cell` on every cell's energy.
+    let spread = |energy: &mut [f32], cell: usize, sign: f32| {
+        let (cx, cy) = (cell % size, cell / size);
+        for (i, e) in energy.iter_mut().enumerate() {
+            let dx = (i % size + size - cx) % size;
+            let dy = (i / size + size - cy) % size;
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+    // The set or unset cell with the highest or lowest energy.
+    let extreme = |energy: &[f32], cells: &[bool], set: bool, highest: bool| {
+        (0..n)
+            .filter(|&i| cells[i] == set)
+            .max_by(|&a, &b| {
+                let order = energy[a].total_cmp(&energy[b]);
+                if highest {
+                    order
+                } else {
+                    order.reverse()
+                }
+            })
+            .unwrap()
+    };
+
+    // A random tenth of the cells, relaxed by moving the tightest cluster into the
+    // largest void until that would put it back where it was.
+    let ones = n / 10;
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut prototype = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let mut placed = 0;
+    while placed < ones {
+        let cell = rng.gen_range(0..n);
+        if !prototype[cell] {
+            prototype[cell] = true;
+            spread(&mut energy, cell, 1.0);
+            placed += 1;
+        }
+    }
+    for _ in 0..n {
+        let cluster = extreme(&energy, &prototype, true, true);
+        prototype[cluster] = false;
+        spread(&mut energy, cluster, -1.0);
+        let void = extreme(&energy, &prototype, false, false);
+        prototype[void] = true;
+        spread(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // Ranks the prototype's cells by removing its tightest clusters first...
+    let mut rank = vec![0; n];
+    let (mut cells, mut e) = (prototype.clone(), energy.clone());
+    for r in (0..ones).rev() {
+        let cluster = extreme(&e, &cells, true, true);
+        cells[cluster] = false;
+        spread(&mut e, cluster, -1.0);
+        rank[cluster] = r;
+    }
+    // ...then fills the largest voids up to half...
+    let (mut cells, mut e) = (prototype, energy);
+    for r in ones..n / 2 {
+        let void = extreme(&e, &cells, false, false);
+        cells[void] = true;
+        spread(&mut e, void, 1.0);
+        rank[void] = r;
+    }
+    // ...and the rest from the tightest clusters of unset cells.
+    let mut e = vec![0.0; n];
+    for cell in (0..n).filter(|&i| !cells[i]) {
+        spread(&mut e, cell, 1.0);
+    }
+    for r in n / 2..n {
+        let cluster = extreme(&e, &cells, false, true);
+        cells[cluster] = true;
+        spread(&mut e, cluster, -1.0);
+        rank[cluster] = r;
+    }
+    rank.into_iter()
+        .map(|r| (r as f32 + 0.5) / n as f32)
+        .collect()
+}
+
+#[test]
+fn test_samplers() {
+    let kinds = [
+        SamplerKind::Random,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+        SamplerKind::BlueNoise,
+    ];
+    for kind in kinds {
+        let draw = |x, y| {
+            let mut sampler = Sampler::new(kind, 5, x, y);
+            (0..16)
+                .flat_map(|i| {
+                    sampler.start_sample(i, 16);
+                    (0..20).map(|_| sampler.gen::<f32>()).collect::<Vec<_>>()
+                })
+                .collect::<Vec<f32>>()
+        };
+        // Seeded per pixel, so reproducible but different between pixels.
+        let values = draw(3, 4);
+        assert_eq!(values, draw(3, 4));
+        assert_ne!(values, draw(4, 3));
+        assert!(values.iter().all(|v| (0.0..1.0).contains(v)));
+    }
+
+    // Sixteen stratified or Sobol samples put one value in each sixteenth of every
+    // dimension.
+    for kind in [SamplerKind::Stratified, SamplerKind::Sobol] {
+        let mut sampler = Sampler::new(kind, 1, 7, 2);
+        let mut strata = [[false; 16]; 12];
+        for i in 0..16 {
+            sampler.start_sample(i, 16);
+            for stratum in strata.iter_mut() {
+                stratum[(sampler.next_f32() * 16.0) as usize] = true;
+            }
+        }
+        assert!(strata.iter().flatten().all(|&hit| hit), "{:?}", kind);
+    }
+
+    // Low-discrepancy samples estimate an integral more closely at equal sample counts.
+    let error = |kind| {
+        let mut total = 0.0;
+        for x in 0..32 {
+            let mut sampler = Sampler::new(kind, 0, x, 0);
+            let mut sum = 0.0;
+            for i in 0..64 {
+                sampler.start_sample(i, 64);
+                let (u, v) = (sampler.next_f32(), sampler.next_f32());
+                sum += u * v;
+            }
+            total += (sum / 64.0 - 0.25f32).abs();
+        }
+        total / 32.0
+    };
+    let random = error(SamplerKind::Random);
+    for kind in [
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+    ] {
+        assert!(error(kind) < random * 0.5, "{:?}", kind);
+    }
+
+    // Neighboring cells of the blue-noise mask differ more than random values would.
+    let mask = blue_noise();
+    let size = BLUE_NOISE_SIZE;
+    let mut difference = 0.0;
+    for i in 0..size * size {
+        difference += (mask[i] - mask[(i + 1) % size + i / size * size]).abs();
+    }
+    assert!(difference / (size * size) as f32 > 0.4);
+}
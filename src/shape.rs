@@ -6,7 +6,10 @@ use rapier3d::{
     prelude::{Shape, *},
 };
 
-use crate::materials::Material;
+use crate::{intersections::Intersection, materials::Material};
+
+// The transform of a group a shape is nested in: `scale` followed by `transform`.
+pub type Placement = (Isometry3<f32>, Scale3<f32>);
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ShapeBase {
@@ -14,6 +17,8 @@ pub struct ShapeBase {
     pub scale: Scale3<f32>,
     pub material: Material,
     pub center: Point3<f32>,
+    // The groups the shape is nested in, innermost first.
+    pub parents: Vec<Placement>,
 }
 
 impl Default for ShapeBase {
@@ -23,13 +28,119 @@ impl Default for ShapeBase {
             scale: Scale3::new(1.0, 1.0, 1.0),
             material: Material::default(),
             center: Point3::new(0.0, 0.0, 0.0),
+            parents: Vec::new(),
         }
     }
 }
 
-pub trait ShapeT {
+pub trait ShapeT: std::fmt::Debug + Send + Sync {
     fn intersect(&self, ray: &Ray) -> Ray;
+    // Appends the shape's intersections with a world space ray to `xs`.
+    fn intersections<'a>(&'a self, ray: &Ray, xs: &mut Vec<Intersection<'a>>);
     fn normal_at(&self, point: &Point3<f32>) -> Vector3<f32>;
+    // The normal used for shading, perturbed by the material's normal map if it has one.
+    fn shading_normal_at(&self, point: &Point3<f32>) -> Vector3<f32> {
+        self.normal_at(point)
+    }
+    fn tangent_at(&self, point: &Point3<f32>) -> Vector3<f32>;
+    // Converts a world space point into the shape's own space, for patterns and any
+    // other lookups that should stick to the shape.
+    fn world_to_object(&self, point: &Point3<f32>) -> Point3<f32>;
+    // Converts a world space normal into the shape's own space. Unlike points, normals
+    // go through the inverse transpose, so that they stay perpendicular to the surface
+    // under uneven scaling. The result is not normalized.
+    fn normal_to_object(&self, normal: &Vector3<f32>) -> Vector3<f32>;
+    // The shape's center in its own space, which textures are mapped around.
+    fn center(&self) -> Point3<f32>;
+    fn material(&self) -> &Material;
+    // Nests the shape in a group placed by `placement`, outside any groups it is
+    // already nested in.
+    fn nest(&mut self, placement: &Placement);
+}
+
+// Intersections and computations refer to the shape that was hit, so shapes compare
+// by identity.
+impl PartialEq for dyn ShapeT + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::addr_eq(self, other)
+    }
+}
+
+// Undoes a transform made of `scale` followed by `transform`.
+pub fn world_to_object(
+    transform: &Isometry3<f32>,
+    scale: &Scale3<f32>,
+    point: &Point3<f32>,
+) -> Point3<f32> {
+    let p = transform.inverse_transform_point(point);
+    Point3::from(p.coords.component_div(&scale.vector))
+}
+
+// Like `world_to_object`, for a normal rather than a point.
+pub fn normal_to_object(
+    transform: &Isometry3<f32>,
+    scale: &Scale3<f32>,
+    normal: &Vector3<f32>,
+) -> Vector3<f32> {
+    transform
+        .inverse_transform_vector(normal)
+        .component_mul(&scale.vector)
+}
+
+// The inverse of `normal_to_object`.
+pub fn normal_to_world(
+    transform: &Isometry3<f32>,
+    scale: &Scale3<f32>,
+    normal: &Vector3<f32>,
+) -> Vector3<f32> {
+    transform.transform_vector(&normal.component_div(&scale.vector))
+}
+
+// Undoes the transforms of the groups in `parents`, outermost first, leaving a world
+// space point in the space the shape's own transform is given in.
+pub fn world_to_parent(parents: &[Placement], point: &Point3<f32>) -> Point3<f32> {
+    parents.iter().rev().fold(*point, |p, (transform, scale)| {
+        world_to_object(transform, scale, &p)
+    })
+}
+
+// Like `world_to_parent`, for a ray. Distances along the ray are unchanged, so
+// intersections found in the parent space hold in world space.
+pub fn ray_to_parent(parents: &[Placement], ray: &Ray) -> Ray {
+    let dir = parents.iter().rev().fold(ray.dir, |v, (transform, scale)| {
+        transform
+            .inverse_transform_vector(&v)
+            .component_div(&scale.vector)
+    });
+    Ray::new(world_to_parent(parents, &ray.origin), dir)
+}
+
+// Like `world_to_parent`, for a normal.
+pub fn normal_to_parent(parents: &[Placement], normal: &Vector3<f32>) -> Vector3<f32> {
+    parents.iter().rev().fold(*normal, |n, (transform, scale)| {
+        normal_to_object(transform, scale, &n)
+    })
+}
+
+// Takes a normal in the space the shape's own transform is given in back out to world
+// space, normalized.
+pub fn normal_from_parent(parents: &[Placement], normal: &Vector3<f32>) -> Vector3<f32> {
+    parents
+        .iter()
+        .fold(*normal, |n, (transform, scale)| {
+            normal_to_world(transform, scale, &n)
+        })
+        .normalize()
+}
+
+// Like `normal_from_parent`, for a direction along the surface.
+pub fn vector_from_parent(parents: &[Placement], vector: &Vector3<f32>) -> Vector3<f32> {
+    parents
+        .iter()
+        .fold(*vector, |v, (transform, scale)| {
+            transform.transform_vector(&v.component_mul(&scale.vector))
+        })
+        .normalize()
 }
 
 impl ShapeBase {
@@ -39,6 +150,36 @@ impl ShapeBase {
     pub fn normal_at(&self, p: &Point3<f32>) -> Vector3<f32> {
         local_normal_at(&self, *p)
     }
+    pub fn world_to_object(&self, p: &Point3<f32>) -> Point3<f32> {
+        world_to_object(
+            &self.transform,
+            &self.scale,
+            &world_to_parent(&self.parents, p),
+        )
+    }
+    pub fn normal_to_object(&self, n: &Vector3<f32>) -> Vector3<f32> {
+        normal_to_object(
+            &self.transform,
+            &self.scale,
+            &normal_to_parent(&self.parents, n),
+        )
+    }
+    // Takes a normal in the shape's own space out to world space, normalized.
+    pub fn normal_to_world(&self, n: &Vector3<f32>) -> Vector3<f32> {
+        normal_from_parent(
+            &self.parents,
+            &normal_to_world(&self.transform, &self.scale, n),
+        )
+    }
+    // Takes a direction in the shape's own space out to world space, normalized.
+    pub fn vector_to_world(&self, v: &Vector3<f32>) -> Vector3<f32> {
+        vector_from_parent(
+            &self.parents,
+            &self
+                .transform
+                .transform_vector(&v.component_mul(&self.scale.vector)),
+        )
+    }
 }
 pub fn local_normal_at(sphere: &ShapeBase, p: Point3<f32>) -> Vector3<f32> {
     let object_point = sphere.transform.inverse() * p;
@@ -70,3 +211,16 @@ fn test_normal_at() {
     let p = s.normal_at(&Point3::new(0.0, 1.70711, -0.70711));
     assert_relative_eq!(p, Vector3::new(0.0, 0.70711, -0.70711), epsilon = 0.0001);
 }
+
+#[test]
+fn test_world_to_object() {
+    let s = ShapeBase {
+        scale: Scale3::new(2.0, 2.0, 2.0),
+        transform: Isometry3::translation(5.0, 0.0, 0.0),
+        ..ShapeBase::default()
+    };
+    assert_relative_eq!(
+        s.world_to_object(&Point3::new(7.0, 1.0, -3.0)),
+        Point3::new(1.0, 0.5, -1.5)
+    );
+}
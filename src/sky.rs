@@ -0,0 +1,204 @@
+use std::f32::consts::PI;
+
+#[cfg(test)]
+use approx::assert_relative_eq;
+use image::{DynamicImage, Rgb, Rgb32FImage};
+use rapier3d::na::{Point3, Vector3};
+
+use crate::{environment::EnvironmentMap, light::PointLight, ray_rgb::RayRgb};
+
+// The sun is placed this far away so that it acts as a directional light.
+const SUN_DISTANCE: f32 = 1.0e5;
+
+// Preetham's analytic daylight model. The sun's `elevation` is measured up from the
+// horizon and its `azimuth` from -Z towards +X, both in radians, matching the center
+// of an `EnvironmentMap`. `turbidity` runs from about 2 for a clear sky to 10 for haze.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sky {
+    pub elevation: f32,
+    pub azimuth: f32,
+    pub turbidity: f32,
+    // Scales the model's luminance, given in kcd/m², into scene units.
+    pub strength: f32,
+    pub sun_strength: f32,
+}
+
+impl Sky {
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32) -> Self {
+        Self {
+            elevation,
+            azimuth,
+            turbidity,
+            strength: 0.1,
+            sun_strength: 5.0,
+        }
+    }
+
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        Vector3::new(
+            self.elevation.cos() * self.azimuth.sin(),
+            self.elevation.sin(),
+            -self.elevation.cos() * self.azimuth.cos(),
+        )
+    }
+
+    // Radiance of the sky along `dir`. Below the horizon the horizon's color is used.
+    pub fn radiance(&self, dir: &Vector3<f32>) -> RayRgb {
+        let dir = dir.normalize();
+        let theta = dir.y.clamp(0.001, 1.0).acos();
+        let theta_s = (PI / 2.0 - self.elevation).clamp(0.0, PI / 2.0);
+        let gamma = dir.dot(&self.sun_direction()).clamp(-1.0, 1.0).acos();
+        let t = self.turbidity;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+        let (t2, s, s2, s3) = (t * t, theta_s, theta_s * theta_s, theta_s.powi(3));
+        let zenith_x = t2 * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s)
+            + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s + 0.00394)
+            + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s + 0.25886);
+        let zenith_yc = t2 * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s)
+            + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s + 0.00516)
+            + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s + 0.26688);
+
+        let luminance_coeffs = [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ];
+        let x_coeffs = [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ];
+        let y_coeffs = [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ];
+        let relative = |k: &[f32; 5]| perez(k, theta, gamma) / perez(k, 0.0, theta_s);
+
+        let luminance = zenith_y * relative(&luminance_coeffs);
+        let x = zenith_x * relative(&x_coeffs);
+        let y = zenith_yc * relative(&y_coeffs);
+        xyy_to_rgb(x, y, luminance) * self.strength
+    }
+
+    // Mean radiance over all directions, from a grid of directions weighted by the
+    // solid angle each covers.
+    pub fn average(&self) -> RayRgb {
+        let (columns, rows) = (32, 16);
+        let (mut sum, mut total) = (RayRgb::black(), 0.0);
+        for row in 0..rows {
+            let theta = (row as f32 + 0.5) / rows as f32 * PI;
+            for column in 0..columns {
+                let phi = (column as f32 + 0.5) / columns as f32 * 2.0 * PI;
+                let dir = Vector3::new(
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                    -theta.sin() * phi.cos(),
+                );
+                sum = sum + self.radiance(&dir) * theta.sin();
+                total += theta.sin();
+            }
+        }
+        sum * (1.0 / total)
+    }
+
+    // Color of sunlight after passing through the atmosphere, from Rayleigh and
+    // aerosol optical depths at red, green and blue wavelengths.
+    pub fn sun_color(&self) -> RayRgb {
+        if self.elevation <= 0.0 {
+            return RayRgb::black();
+        }
+        let zenith_deg = 90.0 - self.elevation.to_degrees();
+        let air_mass = 1.0
+            / ((90.0 - zenith_deg).to_radians().sin() + 0.15 * (93.885 - zenith_deg).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |lambda: f32| {
+            let rayleigh = 0.008735 * lambda.powf(-4.08);
+            let aerosol = beta * lambda.powf(-1.3);
+            (-air_mass * (rayleigh + aerosol)).exp()
+        };
+        RayRgb::new(
+            transmittance(0.68),
+            transmittance(0.55),
+            transmittance(0.44),
+        ) * self.sun_strength
+    }
+
+    // A distant point light along the sun's direction, matching the sky.
+    pub fn sun(&self) -> PointLight {
+        PointLight::new(
+            self.sun_color(),
+            Point3::from(self.sun_direction() * SUN_DISTANCE),
+        )
+    }
+
+    // Bakes the sky into an equirectangular map so it can be importance sampled as the
+    // world's environment.
+    pub fn environment(&self, width: u32, height: u32) -> EnvironmentMap {
+        let image = Rgb32FImage::from_fn(width, height, |x, y| {
+            let phi = ((x as f32 + 0.5) / width as f32 - 0.5) * 2.0 * PI;
+            let theta = (y as f32 + 0.5) / height as f32 * PI;
+            let dir = Vector3::new(
+                theta.sin() * phi.sin(),
+                theta.cos(),
+                -theta.sin() * phi.cos(),
+            );
+            let c = self.radiance(&dir);
+            Rgb([c.r, c.g, c.b])
+        });
+        EnvironmentMap::from_image(&DynamicImage::ImageRgb32F(image))
+    }
+}
+
+fn perez(k: &[f32; 5], theta: f32, gamma: f32) -> f32 {
+    (1.0 + k[0] * (k[1] / theta.cos()).exp())
+        * (1.0 + k[2] * (k[3] * gamma).exp() + k[4] * gamma.cos().powi(2))
+}
+
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> RayRgb {
+    if y <= 0.0 {
+        return RayRgb::black();
+    }
+    let cx = x / y * luminance;
+    let cz = (1.0 - x - y) / y * luminance;
+    RayRgb::new(
+        (3.2406 * cx - 1.5372 * luminance - 0.4986 * cz).max(0.0),
+        (-0.9689 * cx + 1.8758 * luminance + 0.0415 * cz).max(0.0),
+        (0.0557 * cx - 0.2040 * luminance + 1.0570 * cz).max(0.0),
+    )
+}
+
+#[test]
+fn test_sky() {
+    let sky = Sky::new(PI / 4.0, PI / 2.0, 3.0);
+    let half = 2.0_f32.sqrt() / 2.0;
+    assert_relative_eq!(
+        sky.sun_direction(),
+        Vector3::new(half, half, 0.0),
+        epsilon = 0.0001
+    );
+
+    let near_sun = sky.radiance(&Vector3::new(0.6, 0.6, 0.1));
+    let away = sky.radiance(&Vector3::new(-0.6, 0.6, 0.1));
+    assert!(near_sun.g > away.g);
+    let zenith = sky.radiance(&Vector3::new(0.0, 1.0, 0.0));
+    assert!(zenith.b > zenith.r);
+    let dir = Vector3::new(-0.5, 0.4, -0.7);
+    let baked = sky.environment(64, 32).radiance(&dir);
+    assert_relative_eq!(baked.b, sky.radiance(&dir).b, max_relative = 0.05);
+
+    // Sunlight reddens as the sun sets.
+    let noon = Sky::new(PI / 2.0 - 0.1, 0.0, 3.0).sun_color();
+    let dusk = Sky::new(0.05, 0.0, 3.0).sun_color();
+    assert!(dusk.r / dusk.b > noon.r / noon.b);
+    assert!(dusk.g < noon.g);
+    assert_eq!(Sky::new(-0.1, 0.0, 3.0).sun_color(), RayRgb::black());
+}
@@ -1,5 +1,6 @@
 #[cfg(test)]
 use approx::assert_relative_eq;
+use std::f32::consts::PI;
 use std::ops::Mul;
 
 use assert_approx_eq::assert_approx_eq;
@@ -9,7 +10,14 @@ use rapier3d::{
     prelude::*,
 };
 
-use crate::{intersections::*, materials::Material, pattern::Pattern};
+use crate::{
+    intersections::*,
+    materials::Material,
+    pattern::Pattern,
+    shape::{
+        normal_to_object, normal_to_parent, world_to_object, world_to_parent, Placement, ShapeT,
+    },
+};
 #[derive(Debug, Clone, PartialEq)]
 pub struct Sphere {
     pub center: Point3<f32>,
@@ -17,6 +25,8 @@ pub struct Sphere {
     pub transform: Isometry3<f32>,
     pub scale: Scale3<f32>,
     pub material: Material,
+    // The groups the sphere is nested in, innermost first.
+    pub parents: Vec<Placement>,
 }
 impl Sphere {
     pub fn new(center: Point3<f32>, radius: f32) -> Self {
@@ -26,8 +36,23 @@ impl Sphere {
             transform: Isometry3::identity(),
             scale: Scale3::new(1.0, 1.0, 1.0),
             material: Material::default(),
+            parents: Vec::new(),
         }
     }
+
+    pub fn area(&self) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    // Uniformly samples a point on the surface from two numbers in [0, 1),
+    // returning the point and the outward normal there.
+    pub fn sample_surface(&self, u: f32, v: f32) -> (Point3<f32>, Vector3<f32>) {
+        let z = 1.0 - 2.0 * u;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+        let normal = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+        (self.center + normal * self.radius, normal)
+    }
 }
 
 impl Default for Sphere {
@@ -38,10 +63,63 @@ impl Default for Sphere {
             transform: Isometry3::identity(),
             scale: Scale3::new(1.0, 1.0, 1.0),
             material: Material::default(),
+            parents: Vec::new(),
         }
     }
 }
 
+impl ShapeT for Sphere {
+    fn intersect(&self, ray: &Ray) -> Ray {
+        ray.inverse_transform_by(&self.transform)
+    }
+
+    fn intersections<'a>(&'a self, ray: &Ray, xs: &mut Vec<Intersection<'a>>) {
+        if let Some(i) = intersect(self, ray) {
+            xs.extend(i);
+        }
+    }
+
+    fn normal_at(&self, point: &Point3<f32>) -> Vector3<f32> {
+        normal_at(self, *point)
+    }
+
+    fn shading_normal_at(&self, point: &Point3<f32>) -> Vector3<f32> {
+        shading_normal_at(self, *point)
+    }
+
+    fn tangent_at(&self, point: &Point3<f32>) -> Vector3<f32> {
+        tangent_at(self, *point)
+    }
+
+    fn world_to_object(&self, point: &Point3<f32>) -> Point3<f32> {
+        world_to_object(
+            &self.transform,
+            &self.scale,
+            &world_to_parent(&self.parents, point),
+        )
+    }
+
+    fn normal_to_object(&self, normal: &Vector3<f32>) -> Vector3<f32> {
+        normal_to_object(
+            &self.transform,
+            &self.scale,
+            &normal_to_parent(&self.parents, normal),
+        )
+    }
+
+    fn center(&self) -> Point3<f32> {
+        self.center
+    }
+
+    fn material(&self) -> &Material {
+        &self.material
+    }
+
+    fn nest(&mut self, placement: &Placement) {
+        self.parents.push(*placement);
+    }
+}
+
 #[test]
 fn test_lifetime() {
     let s = Sphere::new(Point::new(0.0, 0.0, 0.0), 1.0);
@@ -50,7 +128,7 @@ fn test_lifetime() {
 
     assert_eq!(xs[0].t, 4.0);
     assert_eq!(xs[1].t, 6.0);
-    assert_eq!(xs[0].object, &s);
+    assert_eq!(xs[0].object, &s as &dyn ShapeT);
 }
 
 #[test]
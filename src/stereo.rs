@@ -0,0 +1,139 @@
+#[cfg(test)]
+use approx::assert_relative_eq;
+use image::{ImageBuffer, Rgb};
+#[cfg(test)]
+use rapier3d::na::{Isometry3, Point3};
+
+#[cfg(test)]
+use crate::camera::Projection;
+use crate::{
+    camera::{render_with, Camera, RenderSettings},
+    world::World,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StereoLayout {
+    // Left eye on the left half of the frame, right eye on the right.
+    #[default]
+    SideBySide,
+    // Left eye on the top half, right eye on the bottom.
+    OverUnder,
+    // Red from the left eye and green and blue from the right, for red-cyan glasses.
+    Anaglyph,
+}
+
+// A pair of eyes `interocular` apart, centered on the camera, whose views line up at
+// `convergence` from it. Panoramic cameras render omni-directional stereo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stereo {
+    pub interocular: f32,
+    pub convergence: f32,
+    pub layout: StereoLayout,
+}
+
+impl Stereo {
+    pub fn new(interocular: f32, convergence: f32) -> Self {
+        Self {
+            interocular,
+            convergence,
+            layout: StereoLayout::SideBySide,
+        }
+    }
+
+    // The camera as seen from one eye.
+    pub fn eye(&self, camera: &Camera, left: bool) -> Camera {
+        let side = if left { 1.0 } else { -1.0 };
+        Camera {
+            eye_offset: side * self.interocular / 2.0,
+            convergence: self.convergence,
+            ..*camera
+        }
+    }
+}
+
+pub fn render_stereo(
+    camera: &Camera,
+    world: &World,
+    settings: &RenderSettings,
+    stereo: &Stereo,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let left = render_with(&stereo.eye(camera, true), world, settings);
+    let right = render_with(&stereo.eye(camera, false), world, settings);
+    combine(&left, &right, stereo.layout)
+}
+
+// Packs the two eyes' images into one frame.
+pub fn combine(
+    left: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    right: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    layout: StereoLayout,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let (width, height) = left.dimensions();
+    match layout {
+        StereoLayout::SideBySide => ImageBuffer::from_fn(width * 2, height, |x, y| {
+            if x < width {
+                *left.get_pixel(x, y)
+            } else {
+                *right.get_pixel(x - width, y)
+            }
+        }),
+        StereoLayout::OverUnder => ImageBuffer::from_fn(width, height * 2, |x, y| {
+            if y < height {
+                *left.get_pixel(x, y)
+            } else {
+                *right.get_pixel(x, y - height)
+            }
+        }),
+        StereoLayout::Anaglyph => ImageBuffer::from_fn(width, height, |x, y| {
+            let (l, r) = (left.get_pixel(x, y), right.get_pixel(x, y));
+            Rgb([l.0[0], r.0[1], r.0[2]])
+        }),
+    }
+}
+
+#[test]
+fn test_stereo() {
+    let mut camera = Camera::new(21, 21, std::f32::consts::PI / 2.0);
+    camera.transform = Isometry3::translation(0.0, 0.0, -5.0);
+    let stereo = Stereo::new(0.064, 5.0);
+    let (left, right) = (stereo.eye(&camera, true), stereo.eye(&camera, false));
+
+    // Both eyes see the center pixel at the same point at the convergence distance.
+    let (l, r) = (left.ray_for_pixel(10, 10), right.ray_for_pixel(10, 10));
+    assert_relative_eq!(l.origin, Point3::new(0.032, 0.0, 5.0), epsilon = 0.0001);
+    assert_relative_eq!(r.origin, Point3::new(-0.032, 0.0, 5.0), epsilon = 0.0001);
+    let (lt, rt) = (5.0 / -l.dir.z, 5.0 / -r.dir.z);
+    assert_relative_eq!(l.point_at(lt), r.point_at(rt), epsilon = 0.0001);
+    assert_relative_eq!(l.point_at(lt), Point3::new(0.0, 0.0, 0.0), epsilon = 0.0001);
+
+    // Orthographic eyes start a full interocular distance apart and are sheared in
+    // opposite directions, so that the same pixel lands on the same point of the
+    // convergence plane.
+    camera.projection = Projection::Orthographic { width: 4.0 };
+    let (left, right) = (stereo.eye(&camera, true), stereo.eye(&camera, false));
+    let (l, r) = (left.ray_for_pixel(3, 10), right.ray_for_pixel(3, 10));
+    assert_relative_eq!(l.origin.x - r.origin.x, 0.064, epsilon = 0.0001);
+    let (lt, rt) = (5.0 / -l.dir.z, 5.0 / -r.dir.z);
+    assert_relative_eq!(l.point_at(lt), r.point_at(rt), epsilon = 0.0001);
+
+    // Omni-directional stereo offsets each eye across the ray's heading.
+    camera.projection = Projection::Equirectangular;
+    camera.hsize = 40;
+    camera.vsize = 20;
+    let left = stereo.eye(&camera, true);
+    let ahead = left.ray_through(20.0, 10.0);
+    let aside = left.ray_through(10.0, 10.0);
+    assert_relative_eq!(ahead.origin, Point3::new(0.032, 0.0, 5.0), epsilon = 0.0001);
+    assert_relative_eq!(aside.origin, Point3::new(0.0, 0.0, 5.032), epsilon = 0.0001);
+
+    let red = ImageBuffer::from_pixel(2, 3, Rgb([200, 10, 10]));
+    let cyan = ImageBuffer::from_pixel(2, 3, Rgb([0, 150, 250]));
+    let side_by_side = combine(&red, &cyan, StereoLayout::SideBySide);
+    assert_eq!(side_by_side.dimensions(), (4, 3));
+    assert_eq!(side_by_side.get_pixel(3, 0), &Rgb([0, 150, 250]));
+    let over_under = combine(&red, &cyan, StereoLayout::OverUnder);
+    assert_eq!(over_under.dimensions(), (2, 6));
+    assert_eq!(over_under.get_pixel(0, 2), &Rgb([200, 10, 10]));
+    let anaglyph = combine(&red, &cyan, StereoLayout::Anaglyph);
+    assert_eq!(anaglyph.get_pixel(1, 1), &Rgb([200, 150, 250]));
+}
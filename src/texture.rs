@@ -0,0 +1,298 @@
+use std::{f32::consts::PI, path::Path, sync::Arc};
+
+#[cfg(test)]
+use approx::assert_relative_eq;
+use image::{DynamicImage, ImageResult};
+use rapier3d::na::{Isometry3, Point3, Vector3};
+
+use crate::ray_rgb::RayRgb;
+
+// How a point on an object, relative to its center, is turned into (u, v). Surfaces that
+// carry their own coordinates, like a mesh's per-vertex UVs, skip the mapping and use
+// `Texture::color_at_uv`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UvMapping {
+    Spherical,
+    // Projects along Y, with u along X and v along Z.
+    Planar,
+    Cylindrical,
+    // Maps each face of the enclosing cube to the whole image.
+    Cubic,
+    // Blends planar projections along X, Y and Z by the surface normal, raised to
+    // `sharpness`.
+    Triplanar { sharpness: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageTexture {
+    width: u32,
+    height: u32,
+    pixels: Vec<RayRgb>,
+    average: RayRgb,
+}
+
+impl ImageTexture {
+    pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<Self> {
+        Ok(Self::from_image(&image::open(path)?))
+    }
+
+    pub fn from_image(image: &DynamicImage) -> Self {
+        let rgb = image.to_rgb32f();
+        let pixels: Vec<RayRgb> = rgb
+            .pixels()
+            .map(|p| RayRgb::new(p.0[0], p.0[1], p.0[2]))
+            .collect();
+        let average = pixels.iter().fold(RayRgb::black(), |sum, &p| sum + p)
+            * (1.0 / pixels.len().max(1) as f32);
+        Self {
+            width: rgb.width(),
+            height: rgb.height(),
+            pixels,
+            average,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn average(&self) -> RayRgb {
+        self.average
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> RayRgb {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    // Bilinearly filtered color at (u, v), with v = 0 at the bottom of the image. An
+    // empty image is black everywhere.
+    pub fn sample(&self, u: f32, v: f32, wrap: WrapMode) -> RayRgb {
+        self.sample_wrapping(u, v, wrap, wrap)
+    }
+
+    // Like `sample`, wrapping u and v each their own way.
+    pub fn sample_wrapping(&self, u: f32, v: f32, wrap_u: WrapMode, wrap_v: WrapMode) -> RayRgb {
+        if self.pixels.is_empty() {
+            return RayRgb::black();
+        }
+        let x = u * self.width as f32 - 0.5;
+        let y = (1.0 - v) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let texel = |x: f32, y: f32| {
+            self.pixel(
+                wrap_index(x as i64, self.width, wrap_u),
+                wrap_index(y as i64, self.height, wrap_v),
+            )
+        };
+        let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1.0, y0) * fx;
+        let bottom = texel(x0, y0 + 1.0) * (1.0 - fx) + texel(x0 + 1.0, y0 + 1.0) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+fn wrap_index(i: i64, size: u32, wrap: WrapMode) -> u32 {
+    let size = size as i64;
+    let i = match wrap {
+        WrapMode::Repeat => i.rem_euclid(size),
+        WrapMode::Clamp => i.clamp(0, size - 1),
+        WrapMode::Mirror => {
+            let period = i.rem_euclid(2 * size);
+            if period < size {
+                period
+            } else {
+                2 * size - 1 - period
+            }
+        }
+    };
+    i as u32
+}
+
+// An image mapped onto an object. `transform` and `scale` place the image in the
+// object's space before the mapping is applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Texture {
+    pub image: Arc<ImageTexture>,
+    pub mapping: UvMapping,
+    pub wrap: WrapMode,
+    pub transform: Isometry3<f32>,
+    pub scale: f32,
+}
+
+impl Texture {
+    pub fn new(image: ImageTexture, mapping: UvMapping) -> Self {
+        Self {
+            image: Arc::new(image),
+            mapping,
+            wrap: WrapMode::Repeat,
+            transform: Isometry3::identity(),
+            scale: 1.0,
+        }
+    }
+
+    // Color at `point`, given relative to the object's center, where the object's
+    // surface normal is `normal`.
+    pub fn color_at(&self, point: &Point3<f32>, normal: &Vector3<f32>) -> RayRgb {
+        let p = self.transform.inverse_transform_point(point) / self.scale;
+        let n = self.transform.inverse_transform_vector(normal);
+        match self.mapping {
+            UvMapping::Triplanar { sharpness } => {
+                let weights = n.map(|c| c.abs().powf(sharpness));
+                let total = weights.x + weights.y + weights.z;
+                if total <= 0.0 {
+                    return self.image.sample(p.x, p.z, self.wrap);
+                }
+                (self.image.sample(p.z, p.y, self.wrap) * weights.x
+                    + self.image.sample(p.x, p.z, self.wrap) * weights.y
+                    + self.image.sample(p.x, p.y, self.wrap) * weights.z)
+                    * (1.0 / total)
+            }
+            mapping => {
+                let (u, v) = mapping.uv(&p);
+                self.image.sample(u, v, self.wrap)
+            }
+        }
+    }
+
+    // Color at texture coordinates (u, v) that the surface supplies itself, tiled by
+    // `scale` like mapped coordinates.
+    pub fn color_at_uv(&self, u: f32, v: f32) -> RayRgb {
+        self.image.sample(u / self.scale, v / self.scale, self.wrap)
+    }
+}
+
+// Texture coordinates at barycentric coordinates (b1, b2) of a triangle whose vertices
+// carry `uvs`, the way a mesh interpolates its per-vertex UVs. There is no mesh shape
+// yet; this is what one would feed to `Texture::color_at_uv`.
+pub fn interpolate_uv(uvs: &[(f32, f32); 3], b1: f32, b2: f32) -> (f32, f32) {
+    let b0 = 1.0 - b1 - b2;
+    (
+        uvs[0].0 * b0 + uvs[1].0 * b1 + uvs[2].0 * b2,
+        uvs[0].1 * b0 + uvs[1].1 * b1 + uvs[2].1 * b2,
+    )
+}
+
+impl UvMapping {
+    pub fn uv(&self, p: &Point3<f32>) -> (f32, f32) {
+        match self {
+            UvMapping::Spherical => {
+                let radius = p.coords.magnitude();
+                if radius == 0.0 {
+                    return (0.0, 0.0);
+                }
+                let phi = (p.y / radius).clamp(-1.0, 1.0).acos();
+                (azimuth_u(p), 1.0 - phi / PI)
+            }
+            UvMapping::Planar | UvMapping::Triplanar { .. } => (p.x, p.z),
+            UvMapping::Cylindrical => (azimuth_u(p), p.y),
+            UvMapping::Cubic => cube_uv(p),
+        }
+    }
+}
+
+fn azimuth_u(p: &Point3<f32>) -> f32 {
+    let theta = p.x.atan2(p.z);
+    1.0 - (theta / (2.0 * PI) + 0.5)
+}
+
+fn cube_uv(p: &Point3<f32>) -> (f32, f32) {
+    let abs = p.coords.abs();
+    let coord = abs.max();
+    if coord == 0.0 {
+        return (0.0, 0.0);
+    }
+    let q = p.coords / coord;
+    let (u, v) = if coord == abs.x {
+        if q.x > 0.0 {
+            (-q.z, q.y)
+        } else {
+            (q.z, q.y)
+        }
+    } else if coord == abs.y {
+        if q.y > 0.0 {
+            (q.x, -q.z)
+        } else {
+            (q.x, q.z)
+        }
+    } else if q.z > 0.0 {
+        (q.x, q.y)
+    } else {
+        (-q.x, q.y)
+    };
+    ((u + 1.0) / 2.0, (v + 1.0) / 2.0)
+}
+
+#[cfg(test)]
+fn checker_image() -> ImageTexture {
+    let mut image = image::RgbImage::new(2, 2);
+    image.put_pixel(0, 0, image::Rgb([255, 255, 255]));
+    image.put_pixel(1, 1, image::Rgb([255, 255, 255]));
+    ImageTexture::from_image(&DynamicImage::ImageRgb8(image))
+}
+
+#[test]
+fn test_uv_mappings() {
+    let (u, v) = UvMapping::Spherical.uv(&Point3::new(0.0, 0.0, -1.0));
+    assert_relative_eq!(u, 0.0, epsilon = 0.0001);
+    assert_relative_eq!(v, 0.5, epsilon = 0.0001);
+    let half = 2.0_f32.sqrt() / 2.0;
+    let (u, v) = UvMapping::Spherical.uv(&Point3::new(half, half, 0.0));
+    assert_relative_eq!(u, 0.25, epsilon = 0.0001);
+    assert_relative_eq!(v, 0.75, epsilon = 0.0001);
+
+    let (u, v) = UvMapping::Cylindrical.uv(&Point3::new(0.0, 0.25, 1.0));
+    assert_relative_eq!(u, 0.5, epsilon = 0.0001);
+    assert_relative_eq!(v, 0.25, epsilon = 0.0001);
+
+    assert_eq!(
+        UvMapping::Planar.uv(&Point3::new(0.25, 3.0, 0.5)),
+        (0.25, 0.5)
+    );
+
+    let (u, v) = UvMapping::Cubic.uv(&Point3::new(-0.5, 0.5, 1.0));
+    assert_relative_eq!(u, 0.25, epsilon = 0.0001);
+    assert_relative_eq!(v, 0.75, epsilon = 0.0001);
+}
+
+#[test]
+fn test_image_sampling() {
+    let image = checker_image();
+    assert_eq!(image.sample(0.25, 0.75, WrapMode::Repeat), RayRgb::white());
+    assert_eq!(image.sample(0.75, 0.75, WrapMode::Repeat), RayRgb::black());
+    assert_relative_eq!(image.sample(0.5, 0.75, WrapMode::Repeat).r, 0.5);
+    assert_relative_eq!(image.sample(1.25, 0.75, WrapMode::Repeat).r, 1.0);
+    assert_relative_eq!(image.sample(1.25, 0.75, WrapMode::Clamp).r, 0.0);
+    assert_relative_eq!(image.sample(1.25, 0.75, WrapMode::Mirror).r, 0.0);
+    assert_relative_eq!(image.sample(-0.25, 0.75, WrapMode::Mirror).r, 1.0);
+
+    let empty = ImageTexture::from_image(&DynamicImage::ImageRgb8(image::RgbImage::new(0, 0)));
+    assert_eq!(empty.sample(0.5, 0.5, WrapMode::Repeat), RayRgb::black());
+}
+
+#[test]
+fn test_mesh_uvs() {
+    let uvs = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+    assert_eq!(interpolate_uv(&uvs, 1.0, 0.0), (1.0, 0.0));
+    assert_eq!(interpolate_uv(&uvs, 0.0, 0.0), (0.0, 0.0));
+    let (u, v) = interpolate_uv(&uvs, 0.25, 0.75);
+    assert_relative_eq!(u, 0.25);
+    assert_relative_eq!(v, 0.75);
+
+    let mut texture = Texture::new(checker_image(), UvMapping::Planar);
+    assert_eq!(texture.color_at_uv(u, v), RayRgb::white());
+    assert_eq!(texture.color_at_uv(u + 0.5, v), RayRgb::black());
+    texture.scale = 2.0;
+    assert_eq!(texture.color_at_uv(0.5, 1.5), RayRgb::white());
+}
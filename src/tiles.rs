@@ -0,0 +1,282 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use image::{GenericImage, ImageBuffer, Rgb};
+#[cfg(test)]
+use rapier3d::na::Isometry3;
+
+#[cfg(test)]
+use crate::camera::render_with;
+use crate::{
+    camera::{in_thread_pool, render_region, Camera, Region, RenderSettings},
+    world::World,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BucketOrder {
+    // Row by row from the top left.
+    #[default]
+    Scanline,
+    // Outwards from the center of the image, where the subject usually is.
+    Spiral,
+    // Along a Hilbert curve, so that consecutive tiles are always neighbors.
+    Hilbert,
+}
+
+// How an image is split into square tiles of `size` pixels, and the order they are
+// rendered in. Tiles on the right and bottom edges may be smaller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tiles {
+    pub size: u32,
+    pub order: BucketOrder,
+}
+
+impl Default for Tiles {
+    fn default() -> Self {
+        Self {
+            size: 32,
+            order: BucketOrder::Scanline,
+        }
+    }
+}
+
+impl Tiles {
+    pub fn new(size: u32, order: BucketOrder) -> Self {
+        Self { size, order }
+    }
+
+    // The camera's image cut into tiles, in bucket order.
+    pub fn regions(&self, camera: &Camera) -> Vec<Region> {
+        let size = self.size.max(1);
+        let columns = camera.hsize.div_ceil(size);
+        let rows = camera.vsize.div_ceil(size);
+        let cells: Vec<(u32, u32)> = match self.order {
+            BucketOrder::Scanline => (0..rows)
+                .flat_map(|row| (0..columns).map(move |column| (column, row)))
+                .collect(),
+            BucketOrder::Spiral => spiral(columns, rows),
+            BucketOrder::Hilbert => hilbert(columns, rows),
+        };
+        cells
+            .into_iter()
+            .map(|(column, row)| Region::new(column * size, row * size, size, size).clamp(camera))
+            .collect()
+    }
+}
+
+// Walks a square spiral out from the center cell, keeping the cells inside the grid.
+fn spiral(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let total = (columns * rows) as usize;
+    let mut cells = Vec::with_capacity(total);
+    let (mut x, mut y) = ((columns as i64 - 1) / 2, (rows as i64 - 1) / 2);
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut turn = 0;
+    let mut length = 1;
+    while cells.len() < total {
+        for _ in 0..2 {
+            let (dx, dy) = directions[turn % 4];
+            for _ in 0..length {
+                if (0..columns as i64).contains(&x) && (0..rows as i64).contains(&y) {
+                    cells.push((x as u32, y as u32));
+                }
+                x += dx;
+                y += dy;
+            }
+            turn += 1;
+        }
+        length += 1;
+    }
+    cells
+}
+
+// Follows the Hilbert curve over the smallest power-of-two square covering the grid,
+// keeping the cells inside it.
+fn hilbert(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let n = columns.max(rows).next_power_of_two();
+    (0..n * n)
+        .map(|d| {
+            let (mut x, mut y) = (0, 0);
+            let mut t = d;
+            let mut s = 1;
+            while s < n {
+                let rx = 1 & (t / 2);
+                let ry = 1 & (t ^ rx);
+                if ry == 0 {
+                    if rx == 1 {
+                        x = s - 1 - x;
+                        y = s - 1 - y;
+                    }
+                    std::mem::swap(&mut x, &mut y);
+                }
+                x += s * rx;
+                y += s * ry;
+                t /= 4;
+                s *= 2;
+            }
+            (x, y)
+        })
+        .filter(|&(x, y)| x < columns && y < rows)
+        .collect()
+}
+
+// Stops a render from any thread. Tiles already being rendered are finished.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Sent once for each finished tile. `done` of `total` tiles are finished so far.
+#[derive(Debug)]
+pub struct TileProgress<'a> {
+    pub region: Region,
+    pub pixels: &'a ImageBuffer<Rgb<u8>, Vec<u8>>,
+    pub done: usize,
+    pub total: usize,
+}
+
+impl TileProgress<'_> {
+    pub fn fraction(&self) -> f32 {
+        self.done as f32 / self.total.max(1) as f32
+    }
+}
+
+// Renders the image tile by tile in bucket order, calling `progress` from the rendering
+// thread as each tile finishes. Returns `None` if the render was cancelled.
+pub fn render_tiles<F>(
+    camera: &Camera,
+    world: &World,
+    settings: &RenderSettings,
+    tiles: &Tiles,
+    cancel: &Cancel,
+    progress: F,
+) -> Option<ImageBuffer<Rgb<u8>, Vec<u8>>>
+where
+    F: Fn(&TileProgress) + Sync,
+{
+    let regions = tiles.regions(camera);
+    let image = Mutex::new(ImageBuffer::new(camera.hsize, camera.vsize));
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    // Each tile is rendered by one thread, so threads pick tiles up in bucket order.
+    let tile_settings = RenderSettings {
+        threads: Some(1),
+        ..*settings
+    };
+
+    let work = || {
+        while !cancel.is_cancelled() {
+            let Some(region) = regions.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                break;
+            };
+            let pixels = render_region(camera, world, &tile_settings, region);
+            image
+                .lock()
+                .unwrap()
+                .copy_from(&pixels, region.x, region.y)
+                .unwrap();
+            progress(&TileProgress {
+                region: *region,
+                pixels: &pixels,
+                done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                total: regions.len(),
+            });
+        }
+    };
+    match settings.threads {
+        Some(1) => work(),
+        threads => in_thread_pool(threads, || {
+            rayon::scope(|s| {
+                for _ in 0..rayon::current_num_threads() {
+                    s.spawn(|_| work());
+                }
+            })
+        }),
+    }
+
+    if done.into_inner() < regions.len() {
+        return None;
+    }
+    Some(image.into_inner().unwrap())
+}
+
+#[test]
+fn test_bucket_orders() {
+    let camera = Camera::new(70, 50, std::f32::consts::PI / 2.0);
+    for order in [
+        BucketOrder::Scanline,
+        BucketOrder::Spiral,
+        BucketOrder::Hilbert,
+    ] {
+        let regions = Tiles::new(16, order).regions(&camera);
+        // 5 by 4 tiles, each pixel covered exactly once.
+        assert_eq!(regions.len(), 20);
+        let area: u32 = regions.iter().map(|r| r.width * r.height).sum();
+        assert_eq!(area, 70 * 50);
+        for (i, a) in regions.iter().enumerate() {
+            assert!(regions[i + 1..].iter().all(|b| (a.x, a.y) != (b.x, b.y)));
+        }
+    }
+
+    let scanline = Tiles::new(16, BucketOrder::Scanline).regions(&camera);
+    assert_eq!(scanline[1], Region::new(16, 0, 16, 16));
+    assert_eq!(scanline[4], Region::new(64, 0, 6, 16));
+    let spiral = Tiles::new(16, BucketOrder::Spiral).regions(&camera);
+    assert_eq!((spiral[0].x, spiral[0].y), (32, 16));
+    assert_eq!((spiral[1].x, spiral[1].y), (48, 16));
+
+    let square = Camera::new(64, 64, std::f32::consts::PI / 2.0);
+    let hilbert = Tiles::new(8, BucketOrder::Hilbert).regions(&square);
+    assert_eq!(hilbert.len(), 64);
+    for pair in hilbert.windows(2) {
+        let step = pair[0].x.abs_diff(pair[1].x) + pair[0].y.abs_diff(pair[1].y);
+        assert_eq!(step, 8);
+    }
+}
+
+#[test]
+fn test_render_tiles() {
+    let world = World::default();
+    let mut camera = Camera::new(30, 20, std::f32::consts::PI / 2.0);
+    camera.transform = Isometry3::translation(0.0, 0.0, -4.0);
+    let settings = RenderSettings::default();
+    let tiles = Tiles::new(8, BucketOrder::Spiral);
+
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let image = render_tiles(&camera, &world, &settings, &tiles, &Cancel::new(), |p| {
+        sender.send((p.region, p.done, p.total)).unwrap();
+    });
+    assert_eq!(image, Some(render_with(&camera, &world, &settings)));
+    let reports: Vec<_> = receiver.try_iter().collect();
+    assert_eq!(reports.len(), 12);
+    assert!(reports
+        .iter()
+        .any(|&(_, done, total)| done == 12 && total == 12));
+
+    // Cancelling from inside the first report stops the render before the rest.
+    let cancel = Cancel::new();
+    let serial = RenderSettings {
+        threads: Some(1),
+        ..settings
+    };
+    let finished = AtomicUsize::new(0);
+    let image = render_tiles(&camera, &world, &serial, &tiles, &cancel, |_| {
+        finished.fetch_add(1, Ordering::Relaxed);
+        cancel.cancel();
+    });
+    assert_eq!(image, None);
+    assert_eq!(finished.into_inner(), 1);
+}
@@ -1,17 +1,35 @@
+use crate::background::Background;
 use crate::computation::Computation;
+use crate::environment::EnvironmentMap;
 use crate::intersections::*;
-use crate::light::lighting;
+use crate::light::{diffuse_specular, lighting, surface_color, ENVIRONMENT_DISTANCE};
+use crate::photon::PhotonMap;
 use crate::ray_rgb::RayRgb;
+use crate::shape::ShapeT;
 use crate::{light::PointLight, sphere::Sphere};
 #[cfg(test)]
 use approx::assert_relative_eq;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rapier3d::na::{Isometry3, Point3, Scale3, Vector3};
 use rapier3d::prelude::*;
+use std::f32::consts::PI;
 pub struct World {
     pub lights: Vec<PointLight>,
     pub objects: Vec<Sphere>,
+    // Shapes other than spheres, and the shapes of groups, which `Group::into_shapes`
+    // flattens.
+    pub shapes: Vec<Box<dyn ShapeT>>,
+    pub emitter_samples: usize,
+    pub caustics: Option<PhotonMap>,
+    pub environment: Option<EnvironmentMap>,
+    pub background: Background,
+    // Whether the background also lights the scene as ambient light.
+    pub ambient_background: bool,
 }
 
+// How many times a ray may be reflected or refracted in `color_at`.
+pub const MAX_REFLECTION_DEPTH: usize = 5;
+
 impl Default for World {
     fn default() -> Self {
         let light = PointLight::new(RayRgb::white(), Point3::new(-10.0, 10.0, -10.0));
@@ -24,6 +42,12 @@ impl Default for World {
         Self {
             lights: vec![light],
             objects: vec![sphere1],
+            shapes: Vec::new(),
+            emitter_samples: 16,
+            caustics: None,
+            environment: None,
+            background: Background::default(),
+            ambient_background: false,
         }
     }
 }
@@ -39,47 +63,242 @@ pub fn intersect_world<'a>(world: &'a World, ray: &Ray) -> Vec<Intersection<'a>>
             None => {}
         }
     }
+    for shape in &world.shapes {
+        shape.intersections(ray, &mut intersections);
+    }
     intersections.sort_by(|x, y| (x.t).partial_cmp(&y.t).unwrap());
     intersections
 }
 
+// The closest intersection in front of the ray origin.
+pub fn hit_world<'a>(world: &'a World, ray: &Ray) -> Option<Intersection<'a>> {
+    intersect_world(world, ray).into_iter().find(|i| i.t > 0.0)
+}
+
+// Like `shade_hit_remaining`, with random numbers seeded from the hit, so that the same
+// hit always shades the same way while other hits sample lights independently.
 pub fn shade_hit(world: &World, comps: &Computation) -> RayRgb {
-    let shadowed = is_shadowed(&world, &comps.over_point);
-    lighting(
-        &comps.object.material,
-        &world.lights[0],
-        comps.over_point,
-        comps.eyev,
-        comps.normalv,
-        shadowed,
-        &comps.object,
+    shade_hit_remaining(
+        world,
+        comps,
+        MAX_REFLECTION_DEPTH,
+        &mut ray_rng(&comps.point, &comps.eyev),
     )
 }
 
+pub fn shade_hit_remaining<R: Rng>(
+    world: &World,
+    comps: &Computation,
+    remaining: usize,
+    rng: &mut R,
+) -> RayRgb {
+    let surface = direct_lighting(world, comps, rng);
+    let reflected = reflected_color(world, comps, remaining, rng);
+    let refracted = refracted_color(world, comps, remaining, rng);
+
+    let material = comps.object.material();
+    if material.reflective > 0.0 && material.transparency > 0.0 {
+        let reflectance = schlick(comps);
+        return surface + reflected * reflectance + refracted * (1.0 - reflectance);
+    }
+    surface + reflected + refracted
+}
+
+pub fn direct_lighting<R: Rng>(world: &World, comps: &Computation, rng: &mut R) -> RayRgb {
+    let mut color = comps.object.material().emitted();
+    for light in &world.lights {
+        let shadowed = is_occluded(world, &comps.over_point, &light.position);
+        color = color
+            + lighting(
+                comps.object.material(),
+                light,
+                comps.over_point,
+                comps.eyev,
+                comps.shading_normalv,
+                shadowed,
+                comps.object,
+            );
+    }
+    color = color + emitter_lighting(world, comps, rng) + environment_lighting(world, comps, rng);
+    if world.ambient_background && world.environment.is_none() {
+        let material = comps.object.material();
+        color = color
+            + surface_color(material, comps.object, comps.over_point)
+                * world.background.average()
+                * material.ambient;
+    }
+    if let Some(caustics) = &world.caustics {
+        color = color + caustics.radiance(comps);
+    }
+    color
+}
+
+// Direct light from emissive objects, estimated by sampling points on their surfaces.
+// Each sample is weighted by the solid angle it covers and divided by PI so that a
+// diffuse surface under an emitter filling its hemisphere reflects its own color.
+pub fn emitter_lighting<R: Rng>(world: &World, comps: &Computation, rng: &mut R) -> RayRgb {
+    let material = comps.object.material();
+    let base_color = surface_color(material, comps.object, comps.over_point);
+    let samples = world.emitter_samples.max(1);
+    let mut color = RayRgb::black();
+
+    for emitter in world.objects.iter().filter(|o| o.material.is_emissive()) {
+        if std::ptr::addr_eq(emitter, comps.object) {
+            continue;
+        }
+        for _ in 0..samples {
+            let (p, n) = emitter.sample_surface(rng.gen(), rng.gen());
+            let target = p + n * f32::EPSILON * 100.0;
+            let v = target - comps.over_point;
+            let distance_squared = v.magnitude_squared();
+            let lightv = v.normalize();
+            let cos_light = n.dot(&-lightv);
+            if cos_light <= 0.0 || is_occluded(world, &comps.over_point, &target) {
+                continue;
+            }
+            let weight = cos_light * emitter.area() / (distance_squared * samples as f32 * PI);
+            let light_color = emitter.material.emitted() * weight;
+            color = color
+                + diffuse_specular(
+                    material,
+                    base_color * light_color,
+                    light_color,
+                    lightv,
+                    comps.eyev,
+                    comps.shading_normalv,
+                );
+        }
+    }
+    color
+}
+
+// Light from the environment map, estimated with `emitter_samples` importance-sampled
+// directions and weighted like `emitter_lighting`.
+pub fn environment_lighting<R: Rng>(world: &World, comps: &Computation, rng: &mut R) -> RayRgb {
+    let environment = match &world.environment {
+        Some(environment) => environment,
+        None => return RayRgb::black(),
+    };
+    let material = comps.object.material();
+    let base_color = surface_color(material, comps.object, comps.over_point);
+    let samples = world.emitter_samples.max(1);
+    let mut color = RayRgb::black();
+
+    for _ in 0..samples {
+        let (lightv, radiance, pdf) = match environment.sample(rng) {
+            Some(sample) => sample,
+            None => continue,
+        };
+        let target = comps.over_point + lightv * ENVIRONMENT_DISTANCE;
+        if lightv.dot(&comps.normalv) <= 0.0 || is_occluded(world, &comps.over_point, &target) {
+            continue;
+        }
+        let light_color = radiance * (1.0 / (pdf * samples as f32 * PI));
+        color = color
+            + diffuse_specular(
+                material,
+                base_color * light_color,
+                light_color,
+                lightv,
+                comps.eyev,
+                comps.shading_normalv,
+            );
+    }
+    color
+}
+
+// What a ray sees when it hits nothing.
+pub fn background(world: &World, ray: &Ray) -> RayRgb {
+    match &world.environment {
+        Some(environment) => environment.radiance(&ray.dir.normalize()),
+        None => world.background.color_at(&ray.dir),
+    }
+}
+
+// Like `color_at_remaining`, with random numbers seeded from the ray, so that the same
+// ray always sees the same color while other rays sample lights independently.
 pub fn color_at(world: &World, ray: &Ray) -> RayRgb {
-    let i = intersect_world(&world, &ray);
+    color_at_remaining(
+        world,
+        ray,
+        MAX_REFLECTION_DEPTH,
+        &mut ray_rng(&ray.origin, &ray.dir),
+    )
+}
+
+// Random numbers seeded from every bit of a ray's origin and direction.
+fn ray_rng(origin: &Point3<f32>, dir: &Vector3<f32>) -> StdRng {
+    let seed = origin.iter().chain(dir.iter()).fold(0u64, |seed, c| {
+        (seed ^ c.to_bits() as u64)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            .rotate_left(31)
+    });
+    StdRng::seed_from_u64(seed)
+}
+
+pub fn color_at_remaining<R: Rng>(
+    world: &World,
+    ray: &Ray,
+    remaining: usize,
+    rng: &mut R,
+) -> RayRgb {
+    match hit_world(world, ray) {
+        Some(hit) => {
+            let comps = prepare_computations(&hit, ray);
+            shade_hit_remaining(world, &comps, remaining, rng)
+        }
+        None => background(world, ray),
+    }
+}
+
+pub fn reflected_color<R: Rng>(
+    world: &World,
+    comps: &Computation,
+    remaining: usize,
+    rng: &mut R,
+) -> RayRgb {
+    let reflective = comps.object.material().reflective;
+    if reflective <= 0.0 || remaining == 0 {
+        return RayRgb::black();
+    }
+    let ray = Ray::new(comps.over_point, comps.reflectv);
+    color_at_remaining(world, &ray, remaining - 1, rng) * reflective
+}
 
-    if i.len() > 0 {
-        let comps = prepare_computations(&i[0], &ray);
-        let color = shade_hit(&world, &comps);
-        return color;
+pub fn refracted_color<R: Rng>(
+    world: &World,
+    comps: &Computation,
+    remaining: usize,
+    rng: &mut R,
+) -> RayRgb {
+    let transparency = comps.object.material().transparency;
+    if transparency <= 0.0 || remaining == 0 {
+        return RayRgb::black();
+    }
+    match refract(comps, &-comps.eyev) {
+        Some(direction) => {
+            let ray = Ray::new(comps.under_point, direction);
+            color_at_remaining(world, &ray, remaining - 1, rng) * transparency
+        }
+        None => RayRgb::black(),
     }
-    RayRgb::black()
 }
 
 pub fn is_shadowed(world: &World, point: &Point3<f32>) -> bool {
-    let v = world.lights[0].position - point;
+    is_occluded(world, point, &world.lights[0].position)
+}
+
+// Whether anything lies between `point` and `target`.
+pub fn is_occluded(world: &World, point: &Point3<f32>, target: &Point3<f32>) -> bool {
+    let v = target - point;
     let distance = v.magnitude();
     let direction = v.normalize();
-    let r = Ray::new(point.clone(), direction);
-    let intersections = intersect_world(&world, &r);
-    let intersections: Vec<Intersection> =
-        intersections.into_iter().filter(|&i| i.t >= 0.0).collect();
-
-    if intersections.len() > 0 && intersections[0].t < distance {
-        return true;
-    }
-    false
+    let r = Ray::new(*point, direction);
+    let intersections = intersect_world(world, &r);
+    intersections
+        .iter()
+        .find(|i| i.t >= 0.0)
+        .is_some_and(|i| i.t < distance)
 }
 
 #[test]
@@ -159,3 +378,93 @@ fn test_is_shadowed() {
     let s = is_shadowed(&w, &p);
     assert_eq!(s, false);
 }
+
+#[test]
+fn test_reflection_and_refraction() {
+    let mut w = World::default();
+    let mut mirror = Sphere::new(Point3::new(0.0, -101.0, 0.0), 100.0);
+    mirror.material.reflective = 0.5;
+    w.objects.push(mirror);
+    let r = Ray::new(
+        Point3::new(0.0, 0.0, -5.0),
+        Vector3::new(0.0, -1.0, 2.5).normalize(),
+    );
+    let c = color_at(&w, &r);
+    let matte = {
+        let mut w = World::default();
+        w.objects
+            .push(Sphere::new(Point3::new(0.0, -101.0, 0.0), 100.0));
+        color_at(&w, &r)
+    };
+    assert!(c.r > matte.r);
+
+    let mut w = World::default();
+    w.objects[0].material.transparency = 1.0;
+    w.objects[0].material.refractive_index = 1.5;
+    w.objects[0].material.diffuse = 0.0;
+    w.objects[0].material.ambient = 0.0;
+    w.objects[0].material.specular = 0.0;
+    let mut backdrop = Sphere::new(Point3::new(0.0, 0.0, 104.0), 100.0);
+    backdrop.material.color = RayRgb::new(0.0, 1.0, 0.0);
+    backdrop.material.ambient = 1.0;
+    w.objects.push(backdrop);
+    let r = Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+    let c = color_at(&w, &r);
+    assert_relative_eq!(c.r, 0.0, epsilon = 0.0001);
+    assert!(c.g > 0.9);
+}
+
+#[test]
+fn test_environment_lighting() {
+    let sky = image::Rgb32FImage::from_pixel(8, 4, image::Rgb([0.5, 0.6, 0.8]));
+    let mut w = World {
+        lights: vec![],
+        emitter_samples: 256,
+        environment: Some(EnvironmentMap::from_image(
+            &image::DynamicImage::ImageRgb32F(sky),
+        )),
+        ..World::default()
+    };
+    w.objects[0].material.ambient = 0.0;
+    w.objects[0].material.specular = 0.0;
+
+    let r = Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 1.0, 0.0));
+    let c = color_at(&w, &r);
+    assert_relative_eq!(c.b, 0.8, epsilon = 0.0001);
+
+    // A diffuse sphere under a uniform sky reflects the sky times its albedo.
+    let r = Ray::new(Point3::new(0.0, 0.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+    let c = color_at(&w, &r);
+    assert_relative_eq!(c.r, 0.8 * 0.9 * 0.5, max_relative = 0.15);
+}
+
+#[test]
+fn test_emissive_objects() {
+    let mut lamp = Sphere::new(Point3::new(0.0, 3.0, 0.0), 1.0);
+    lamp.material.emission = RayRgb::new(1.0, 0.9, 0.8);
+    lamp.material.emission_strength = 4.0;
+    let floor = Sphere::new(Point3::new(0.0, -100.0, 0.0), 100.0);
+    let w = World {
+        lights: vec![],
+        objects: vec![lamp, floor],
+        emitter_samples: 64,
+        ..World::default()
+    };
+
+    let r = Ray::new(Point3::new(0.0, 3.0, -5.0), Vector3::new(0.0, 0.0, 1.0));
+    let c = color_at(&w, &r);
+    assert!(c.r >= 4.0 && c.g >= 3.6 && c.b >= 3.2);
+
+    let r = Ray::new(
+        Point3::new(0.0, 1.0, -5.0),
+        Vector3::new(0.0, -0.2, 1.0).normalize(),
+    );
+    let c = color_at(&w, &r);
+    assert!(c.r > 0.0 && c.r > c.b);
+    // Sampling the lamp is repeatable.
+    assert_eq!(color_at(&w, &r), c);
+
+    let r = Ray::new(Point3::new(0.0, 1.0, -5.0), Vector3::new(0.0, 0.0, -1.0));
+    let c = color_at(&w, &r);
+    assert_eq!(c, RayRgb::black());
+}